@@ -0,0 +1,172 @@
+//! Plays the game without opening a window by feeding random actions to the
+//! simulation, useful for soak testing the game logic.
+//!
+//! Usage: headless [turns] [seed] [--record file]
+//!        headless --replay file
+
+use rand::{seq::SliceRandom, Rng};
+use rand_seeder::{Seeder, SipRng};
+use roguelike_rust::{
+    game::{self, Action, GameState, Replay, Simulation},
+    geom::pt,
+    raws::{Raws, RawsError},
+};
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+};
+
+const WIDTH: i32 = 60;
+const HEIGHT: i32 = 35;
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let record = take_flag(&mut args, "--record");
+    if let Some(path) = take_flag(&mut args, "--replay") {
+        replay(path);
+        return;
+    }
+    let turns: usize = arg(&args, 0, "number of turns").unwrap_or(1000);
+    let seed: u64 = arg(&args, 1, "seed").unwrap_or_else(rand::random);
+
+    let world = hecs::World::new();
+    let hero = world.reserve_entity();
+    let (map, _) = match game::generate_map(WIDTH, HEIGHT, seed, 0) {
+        Ok(generated) => generated,
+        Err(e) => {
+            eprintln!("unable to generate the first level: {}", e);
+            process::exit(2);
+        }
+    };
+    let mut state = GameState::new(world, hero, map, seed, load_raws());
+    let mut sim = Simulation::new(&mut state);
+    let mut recording = Replay::new(&state);
+
+    // The hero's actions are seeded too so a run can be repeated
+    let mut rng: SipRng = Seeder::from((seed, "headless")).make_rng();
+    let mut level_changes = 0;
+    let mut played = 0;
+    for _ in 0..turns {
+        if state.hero_dead() {
+            break;
+        }
+        let action = random_action(&mut rng);
+        recording.record(&state, action);
+        if sim.step(&mut state, Some(action)) {
+            level_changes += 1;
+        }
+        sim.animate(&mut state);
+        played += 1;
+    }
+
+    let (hp, max_hp) = state.hero_hp().unwrap_or((0, 0));
+    println!("Seed:          {}", seed);
+    println!("Turns played:  {}", played);
+    println!("Depth:         {}", state.depth());
+    println!("Level changes: {}", level_changes);
+    println!("Hero HP:       {}/{}", hp, max_hp);
+    println!("Entities:      {}", state.entity_count());
+    println!("State hash:    {:016x}", state.state_hash());
+
+    if let Some(path) = record {
+        recording.finish(&state);
+        if let Err(e) = recording.save(&path) {
+            eprintln!("unable to write replay {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Plays back the replay at path and exits with an error if it ends up
+/// differently than when it was recorded
+fn replay(path: PathBuf) {
+    let replay = match Replay::load(&path) {
+        Ok(replay) => replay,
+        Err(e) => {
+            eprintln!("unable to read replay {}: {}", path.display(), e);
+            process::exit(2);
+        }
+    };
+    let mut state = match replay.new_game(load_raws()) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("unable to generate the first level: {}", e);
+            process::exit(2);
+        }
+    };
+    let mut sim = Simulation::new(&mut state);
+    for n in 0..replay.len() {
+        let action = match replay.action(n, &state) {
+            Some(action) => action,
+            None => {
+                eprintln!("replay no longer matches the game at action {}", n);
+                process::exit(1);
+            }
+        };
+        sim.step(&mut state, Some(action));
+        sim.animate(&mut state);
+    }
+
+    println!("Seed:          {}", replay.seed());
+    println!("Actions:       {}", replay.len());
+    println!("State hash:    {:016x}", state.state_hash());
+    if replay.verify(&state) {
+        println!("Replay matches the recorded game.");
+    } else {
+        eprintln!("replay ended up differently than the recorded game");
+        process::exit(1);
+    }
+}
+
+/// The definitions in resources/raws.ron, the same ones the game loads, or
+/// the built in ones if there is no such file
+fn load_raws() -> Raws {
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".to_string());
+    let path = Path::new(&dir).join("resources/raws.ron");
+    match Raws::load(&path) {
+        Ok(raws) => raws,
+        Err(RawsError::Io(e)) if e.kind() == io::ErrorKind::NotFound => Raws::builtin(),
+        Err(e) => {
+            eprintln!("unable to load {}: {}", path.display(), e);
+            process::exit(2);
+        }
+    }
+}
+
+/// Removes flag and the value following it from args
+fn take_flag(args: &mut Vec<String>, flag: &str) -> Option<PathBuf> {
+    let i = args.iter().position(|a| a == flag)?;
+    args.remove(i);
+    if i >= args.len() {
+        eprintln!("{} needs a file", flag);
+        process::exit(2);
+    }
+    Some(PathBuf::from(args.remove(i)))
+}
+
+fn arg<T: FromStr>(args: &[String], n: usize, what: &str) -> Option<T>
+where
+    T::Err: std::fmt::Display,
+{
+    let a = args.get(n)?;
+    match a.parse() {
+        Ok(v) => Some(v),
+        Err(e) => {
+            eprintln!("invalid {} {}: {}", what, a, e);
+            process::exit(2);
+        }
+    }
+}
+
+fn random_action(rng: &mut impl Rng) -> Action {
+    let moves = [pt(0, -1), pt(0, 1), pt(-1, 0), pt(1, 0)];
+    match rng.gen_range(0..20) {
+        0 => Action::Wait,
+        1 => Action::PickUp,
+        2 => Action::Descend,
+        3 => Action::Ascend,
+        _ => Action::Move(*moves.choose(rng).unwrap()),
+    }
+}
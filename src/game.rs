@@ -1,45 +1,419 @@
 use crate::{
     fov,
-    geom::{pt, Grid, Point},
+    geom::{line, pt, Grid, Point},
     gfx::{self, Renderable},
+    mapgen::{
+        self, BspGenerator, CaveGenerator, CullUnreachable, DlaGenerator, Doors, DrunkardGenerator,
+        GenerationError, Mirror, Pipeline, PlaceVaults, Region, SimpleMapGenerator, StairsFarApart,
+        Symmetry, Tag, Vault, WalkSettings, WidenCorridors,
+    },
+    pathfinding::{self, DijkstraMap, Movement},
+    raws::{Behaviour, Def, Raws, RawsError, Slot},
     scene::{Scene, Transition},
+    text,
 };
+use core::time;
+use euclid::Box2D;
 use ggez::{
     glam::*,
     graphics,
     input::keyboard::{KeyCode, KeyInput, KeyMods},
     Context, GameResult,
 };
-use core::time;
+use rand::{seq::SliceRandom, Rng};
+use rand_seeder::{Seeder, SipRng};
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+mod log;
+mod replay;
+mod save;
+pub use log::MessageLog;
+pub use replay::Replay;
+pub use save::SaveError;
 
 pub struct GameState {
     world: hecs::World,
     hero: hecs::Entity,
-    sprite_set: gfx::SpriteSet,
+    /// Definitions of everything that can be spawned
+    raws: Raws,
     chan: shrev::EventChannel<Event>,
     pub map: Map,
+    /// Every random choice in the game is derived from this
+    seed: u64,
+    /// Number of actions the hero has taken
+    turn: u64,
+    depth: usize,
+    /// Levels the hero is not on, indexed by depth
+    levels: Vec<Option<Level>>,
     pub input: KeyState,
+    pub log: MessageLog,
+    /// Action chosen outside of the Game scene, performed on its next update
+    pending_action: Option<Action>,
 }
 
 impl GameState {
-    pub fn new(
-        world: hecs::World,
-        hero: hecs::Entity,
-        sprite_set: gfx::SpriteSet,
-        map: Map,
-    ) -> Self {
+    pub fn new(world: hecs::World, hero: hecs::Entity, map: Map, seed: u64, raws: Raws) -> Self {
+        let mut world = world;
+        world
+            .insert(
+                hero,
+                (
+                    Player,
+                    Name("Hero".to_string()),
+                    Position(map.entrance),
+                    BlocksTile,
+                    gfx::Renderable {
+                        spr: gfx::CP437::ChAt,
+                        color: gfx::WHITE_BRIGHT,
+                    },
+                    Viewshed {
+                        visible_tiles: HashSet::new(),
+                        range: 7,
+                        dirty: true,
+                    },
+                    CombatStats::new(30, 5, 2),
+                ),
+            )
+            .expect("hero entity missing");
+        spawn_level_entities(&mut world, &raws, &map, seed, 0);
         GameState {
             world,
             hero,
-            sprite_set,
+            raws,
             map,
+            seed,
+            turn: 0,
+            depth: 0,
+            levels: vec![],
             input: KeyState::default(),
+            log: MessageLog::default(),
             chan: shrev::EventChannel::new(),
+            pending_action: None,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), SaveError> {
+        save::write(self, path)
+    }
+
+    /// Continues the game saved at path, raws are not saved and must be the
+    /// ones the game was started with
+    pub fn load(path: &Path, raws: Raws) -> Result<Self, SaveError> {
+        save::read(path, raws)
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn turn(&self) -> u64 {
+        self.turn
+    }
+
+    /// Random numbers for one part of the game during the current turn. Each
+    /// stream is derived from the seed alone so that the same seed and actions
+    /// always play out the same, no matter what else drew random numbers.
+    fn rng(&self, stream: &str) -> SipRng {
+        Seeder::from((self.seed, stream, self.turn)).make_rng()
+    }
+
+    /// Current and max hit points of the hero
+    pub fn hero_hp(&self) -> Option<(i32, i32)> {
+        let stats = self.world.get::<&CombatStats>(self.hero).ok()?;
+        Some((stats.hp, stats.max_hp))
+    }
+
+    /// Whether the game is over, the dead can not act any more
+    pub fn hero_dead(&self) -> bool {
+        self.hero_hp().is_none_or(|(hp, _)| hp <= 0)
+    }
+
+    pub fn hero_position(&self) -> Option<Point> {
+        self.world.get::<&Position>(self.hero).ok().map(|p| p.0)
+    }
+
+    /// Spawns the monster, item or trap defined as name at pos
+    pub fn spawn(&mut self, name: &str, pos: Point) -> Result<hecs::Entity, RawsError> {
+        spawn_named(&mut self.world, &self.raws, name, pos)
+    }
+
+    pub fn entity_count(&self) -> u32 {
+        self.world.len()
+    }
+
+    /// Hash of the whole game, equal for two runs that played out the same
+    pub fn state_hash(&self) -> u64 {
+        replay::state_hash(self)
+    }
+
+    /// Moves the hero and everything it carries to the level at depth, storing
+    /// the rest of the current level until the hero returns. Returns false,
+    /// leaving the hero where it is, if the level could not be generated.
+    fn change_level(&mut self, depth: usize) -> bool {
+        let visited = matches!(self.levels.get(depth), Some(Some(_)));
+        let generated = if visited {
+            None
+        } else {
+            let (width, height) = (self.map.tiles.width, self.map.tiles.height);
+            match generate_map(width, height, self.seed, depth) {
+                Ok((map, _)) => Some(map),
+                Err(e) => {
+                    self.log
+                        .add(gfx::RED, format!("The way is blocked, {}.", e));
+                    return false;
+                }
+            }
+        };
+
+        let carried: HashSet<hecs::Entity> = backpack(&self.world, self.hero).into_iter().collect();
+        let leaving: Vec<hecs::Entity> = self
+            .world
+            .iter()
+            .map(|e| e.entity())
+            .filter(|e| *e != self.hero && !carried.contains(e))
+            .collect();
+        let mut stored = hecs::World::new();
+        for e in leaving {
+            stored.spawn(self.world.take(e).expect("entity missing"));
+        }
+
+        let map = match self.levels.get_mut(depth).and_then(Option::take) {
+            Some(level) => {
+                let mut level_world = level.world;
+                let entities: Vec<hecs::Entity> = level_world.iter().map(|e| e.entity()).collect();
+                for e in entities {
+                    self.world
+                        .spawn(level_world.take(e).expect("entity missing"));
+                }
+                level.map
+            }
+            None => {
+                let map = generated.expect("new levels are generated above");
+                spawn_level_entities(&mut self.world, &self.raws, &map, self.seed, depth);
+                map
+            }
+        };
+        let previous = std::mem::replace(&mut self.map, map);
+        if self.levels.len() <= self.depth {
+            self.levels.resize_with(self.depth + 1, || None);
         }
+        self.levels[self.depth] = Some(Level {
+            map: previous,
+            world: stored,
+        });
+
+        // Arrive on the stairs leading back where the hero came from
+        let arrival = if depth > self.depth {
+            self.map.entrance
+        } else {
+            self.map.exit
+        };
+        self.depth = depth;
+        self.log
+            .add(gfx::CYAN, format!("You arrive at depth {}.", depth + 1));
+        if let Ok((pos, viewshed)) = self
+            .world
+            .query_one_mut::<(&mut Position, &mut Viewshed)>(self.hero)
+        {
+            pos.0 = arrival;
+            viewshed.dirty = true;
+        }
+        true
     }
 }
 
+struct Level {
+    map: Map,
+    world: hecs::World,
+}
+
+/// Where the game is saved to and loaded from
+pub fn save_path(ctx: &Context) -> PathBuf {
+    ctx.fs.user_data_dir().join("savegame")
+}
+
+/// Where the actions of the current game are recorded to
+pub fn replay_path(ctx: &Context) -> PathBuf {
+    ctx.fs.user_data_dir().join("replay")
+}
+
+/// Shallowest depth with caves dug by random walks
+const WALKED_CAVE_DEPTH: usize = 2;
+
+/// Levels which fail to generate are started over this many times before
+/// giving up
+const MAP_ATTEMPTS: usize = 5;
+
+/// Generates the map for depth, also returning the timeline of every stage
+/// that built it. The layout the generator returns is kept with the map.
+pub fn generate_map(
+    width: i32,
+    height: i32,
+    seed: u64,
+    depth: usize,
+) -> Result<(Map, Vec<Grid<graphics::Color>>), GenerationError> {
+    let mut rng: SipRng = Seeder::from((seed, "mapgen", depth)).make_rng();
+    let mut attempt = 1;
+    loop {
+        match level_pipeline(&mut rng, width, height, depth).build(&mut rng, width, height) {
+            Err(_) if attempt < MAP_ATTEMPTS => attempt += 1,
+            result => return result,
+        }
+    }
+}
+
+/// Picks how the level at depth is built, each kind allowed at that depth
+/// being equally likely
+fn level_pipeline(rng: &mut impl Rng, width: i32, height: i32, depth: usize) -> Pipeline {
+    let kinds = if depth >= WALKED_CAVE_DEPTH { 5 } else { 3 };
+    match rng.gen_range(0..kinds) {
+        0 => {
+            let mirror = if rng.gen() {
+                Mirror::Horizontal
+            } else {
+                Mirror::Vertical
+            };
+            Pipeline::new(CaveGenerator::new(width, height))
+                .then(Symmetry(mirror))
+                .then(StairsFarApart)
+        }
+        1 => Pipeline::new(BspGenerator::new(width, height))
+            .then(WidenCorridors)
+            .then(Doors),
+        3 => Pipeline::new(DrunkardGenerator::new(
+            width,
+            height,
+            WalkSettings::default(),
+        ))
+        .then(StairsFarApart),
+        4 => Pipeline::new(DlaGenerator::new(width, height, 35)).then(StairsFarApart),
+        _ => Pipeline::new(SimpleMapGenerator::new(width, height))
+            .then(CullUnreachable)
+            .then(Doors)
+            .then(StairsFarApart)
+            .then(PlaceVaults::new(Vault::builtin(), depth, 1)),
+    }
+}
+
+/// Most monsters and items a room can get at depth 0, deeper levels get more
+const SPAWNS_PER_ROOM: usize = 2;
+/// Deepest level which still gets more spawns than the one above it
+const SPAWN_DEPTH_CAP: usize = 6;
+
+/// Populates a freshly generated map with what its vaults hold and its other
+/// rooms from the spawn table, never on walls, stairs or another spawn
+fn spawn_level_entities(world: &mut hecs::World, raws: &Raws, map: &Map, seed: u64, depth: usize) {
+    let mut used = HashSet::new();
+    for (p, name) in &map.layout.spawns {
+        // vaults.ron may name something raws.ron does not define, the vault
+        // is then left without it rather than stopping the game
+        if let Err(e) = spawn_named(world, raws, name, *p) {
+            eprintln!("skipping vault spawn at {:?}: {}", p, e);
+            continue;
+        }
+        used.insert(*p);
+    }
+
+    let mut rng: SipRng = Seeder::from((seed, "spawn", depth)).make_rng();
+    let max_spawns = SPAWNS_PER_ROOM + depth.min(SPAWN_DEPTH_CAP);
+    for (i, room) in map.layout.rooms.iter().enumerate() {
+        if map.layout.has_tag(Region::Room(i), Tag::Vault) {
+            continue;
+        }
+        let mut free: Vec<Point> = room
+            .y_range()
+            .flat_map(|y| room.x_range().map(move |x| pt(x, y)))
+            .filter(|&p| map.tiles.in_bounds(p) && map.tiles[p] == Tile::Floor)
+            .filter(|p| *p != map.entrance && *p != map.exit && !used.contains(p))
+            .collect();
+        let count = rng.gen_range(0..=max_spawns).min(free.len());
+        let (chosen, _) = free.partial_shuffle(&mut rng, count);
+        for &p in chosen.iter() {
+            let name = match raws.roll_spawn(depth, &mut rng) {
+                Some(name) => name,
+                None => return,
+            };
+            spawn_named(world, raws, name, p).expect("spawn table names are validated");
+            used.insert(p);
+        }
+    }
+}
+
+/// Builds the monster, item or trap defined as name at pos
+fn spawn_named(
+    world: &mut hecs::World,
+    raws: &Raws,
+    name: &str,
+    pos: Point,
+) -> Result<hecs::Entity, RawsError> {
+    let mut builder = hecs::EntityBuilder::new();
+    let (glyph, color) = match raws.get(name)? {
+        Def::Monster(m) => {
+            builder.add(AI::default()).add(BlocksTile).add(Viewshed {
+                visible_tiles: HashSet::new(),
+                range: m.vision,
+                dirty: true,
+            });
+            builder.add(CombatStats::new(m.stats.hp, m.stats.power, m.stats.defense));
+            if m.behaviour == Behaviour::Coward {
+                builder.add(Coward);
+            }
+            (m.glyph, &m.color)
+        }
+        Def::Item(i) => {
+            builder.add(Item);
+            if let Some(eq) = &i.equippable {
+                let slot = match eq.slot {
+                    Slot::Melee => EquipmentSlot::Melee,
+                    Slot::Shield => EquipmentSlot::Shield,
+                };
+                builder.add(Equippable {
+                    slot,
+                    power: eq.power,
+                    defense: eq.defense,
+                });
+            }
+            if let Some(amount) = i.healing {
+                builder.add(ProvidesHealing { amount });
+            }
+            if let Some(amount) = i.damage {
+                builder.add(InflictsDamage { amount });
+            }
+            if let Some(range) = i.range {
+                builder.add(Ranged { range });
+            }
+            if let Some(x) = &i.explosive {
+                builder.add(Explosive {
+                    radius: x.radius,
+                    damage: x.damage,
+                });
+            }
+            (i.glyph, &i.color)
+        }
+        Def::Trap(t) => {
+            builder.add(Explosive {
+                radius: t.explosive.radius,
+                damage: t.explosive.damage,
+            });
+            (t.glyph, &t.color)
+        }
+    };
+    builder
+        .add(Name(name.to_string()))
+        .add(Position(pos))
+        .add(Renderable {
+            spr: glyph,
+            color: gfx::color_named(color).expect("color is checked when parsing"),
+        });
+    Ok(world.spawn(builder.build()))
+}
+
 #[derive(Debug, Default)]
 pub struct KeyState {
     pub key: Option<KeyCode>,
@@ -47,89 +421,65 @@ pub struct KeyState {
     pub repeat: bool,
 }
 
-pub struct Game {
-    instances: graphics::InstanceArray,
-    width: i32,
-    height: i32,
+/// Something the hero does which takes a turn
+#[derive(Clone, Copy, Debug)]
+pub enum Action {
+    Move(Point),
+    Wait,
+    PickUp,
+    Drop(hecs::Entity),
+    Use(hecs::Entity),
+    Throw(hecs::Entity, Point),
+    Zap(hecs::Entity, Point),
+    Descend,
+    Ascend,
+}
+
+/// Runs the rules of the game one turn at a time, independent of any rendering
+/// so that it can be driven headless as well as by the Game scene
+pub struct Simulation {
     move_reader: shrev::ReaderId<Event>,
     collision_reader: shrev::ReaderId<Event>,
     damage_reader: shrev::ReaderId<Event>,
-    dt: time::Duration,
+    death_reader: shrev::ReaderId<Event>,
+    item_reader: shrev::ReaderId<Event>,
+    stairs_reader: shrev::ReaderId<Event>,
 }
 
-impl Game {
-    pub fn new(ctx: &mut Context, state: &mut GameState, width: i32, height: i32) -> Self {
-        let mut instances = graphics::InstanceArray::new(ctx, state.sprite_set.img.clone());
-        instances.resize(ctx, (width * height) as u32 + 50); // mapsize + 50 entities
-
-        state
-            .world
-            .insert(
-                state.hero,
-                (
-                    Player,
-                    Name("Hero".to_string()),
-                    Position(state.map.entrance),
-                    BlocksTile,
-                    gfx::Renderable {
-                        spr: gfx::CP437::ChAt,
-                        color: gfx::WHITE_BRIGHT,
-                    },
-                    Viewshed {
-                        visible_tiles: HashSet::new(),
-                        range: 7,
-                        dirty: true,
-                    },
-                ),
-            )
-            .expect("hero entity missing");
-        state.world.spawn((
-            Name("Giant Ant".to_string()),
-            AI,
-            Position(pt(20, 13)),
-            BlocksTile,
-            gfx::Renderable {
-                spr: gfx::CP437::Cha,
-                color: gfx::BLUE_BRIGHT,
-            },
-        ));
-        state.world.spawn((
-            Name("Giant Ant".to_string()),
-            AI,
-            Position(state.map.entrance + pt(10, -1).to_vector()),
-            BlocksTile,
-            gfx::Renderable {
-                spr: gfx::CP437::Cha,
-                color: gfx::BLUE_BRIGHT,
-            },
-        ));
-        state.world.spawn((
-            Name("Exploding Flask".to_string()),
-            Position(state.map.entrance + pt(1, -1).to_vector()),
-            gfx::Renderable {
-                spr: gfx::CP437::Trap,
-                color: gfx::YELLOW_BRIGHT,
-            },
-            Explosive { radius: 3 },
-        ));
-        Game {
-            instances,
-            width,
-            height,
+impl Simulation {
+    pub fn new(state: &mut GameState) -> Self {
+        Simulation {
             move_reader: state.chan.register_reader(),
             collision_reader: state.chan.register_reader(),
             damage_reader: state.chan.register_reader(),
-            dt: time::Duration::default(),
+            death_reader: state.chan.register_reader(),
+            item_reader: state.chan.register_reader(),
+            stairs_reader: state.chan.register_reader(),
         }
     }
-}
 
-impl Scene<GameState> for Game {
-    fn update(&mut self, ctx: &mut Context, state: &mut GameState) -> Transition<GameState> {
+    /// Performs the hero's action, if any, and lets the rest of the world react
+    /// to it. Returns true if the hero moved to another level.
+    pub fn step(&mut self, state: &mut GameState, action: Option<Action>) -> bool {
         map_indexing_handler(&state.world, &mut state.map);
-        if input_handler(&state.input, state.hero, &mut state.chan) {
+        // Nothing happens any more once the hero is dead
+        let action = action.filter(|_| !state.hero_dead());
+        if let Some(action) = action {
+            // Effects only last until the hero acts again, so how long they
+            // were shown for never changes the outcome of a turn
+            clear_explosions(&mut state.world);
+            state.turn += 1;
+            state.log.set_turn(state.turn);
+            action_handler(action, state.hero, &mut state.chan);
             // Monsters only act when the player acts
-            ai_handler(&state.world, &mut state.chan);
+            let mut rng = state.rng("ai");
+            ai_handler(
+                &mut state.world,
+                &state.map,
+                state.hero,
+                &mut state.chan,
+                &mut rng,
+            );
         }
         move_handler(
             &mut state.world,
@@ -142,13 +492,192 @@ impl Scene<GameState> for Game {
             &state.map,
             &mut state.chan,
             &mut self.collision_reader,
+            &mut state.log,
+        );
+        item_handler(
+            &mut state.world,
+            &state.map,
+            &mut state.chan,
+            &mut self.item_reader,
+            &mut state.log,
+        );
+        damage_handler(
+            &mut state.world,
+            &mut state.chan,
+            &mut self.damage_reader,
+            &mut state.log,
+        );
+        death_handler(
+            &mut state.world,
+            state.hero,
+            &state.chan,
+            &mut self.death_reader,
+            &mut state.log,
+        );
+        let depth = stairs_handler(
+            &state.world,
+            &state.map,
+            state.depth,
+            &state.chan,
+            &mut self.stairs_reader,
+            &mut state.log,
         );
-        damage_handler(&mut state.world, &state.chan, &mut self.damage_reader);
+        let changed = match depth {
+            Some(depth) => state.change_level(depth),
+            None => false,
+        };
         fov_handler(&mut state.world, &state.map.tiles, &mut state.map.explored);
-        
+        changed
+    }
+
+    /// Advances short lived visual effects such as explosions
+    pub fn animate(&mut self, state: &mut GameState) {
+        explosion_handler(&mut state.world);
+    }
+}
+
+pub struct Game {
+    instances: graphics::InstanceArray,
+    sprite_set: gfx::SpriteSet,
+    width: i32,
+    height: i32,
+    sim: Simulation,
+    replay: ReplayMode,
+    quit: bool,
+    dt: time::Duration,
+}
+
+enum ReplayMode {
+    Off,
+    Recording(Replay),
+    Playing { replay: Replay, next: usize },
+}
+
+impl Game {
+    pub fn new(
+        ctx: &mut Context,
+        state: &mut GameState,
+        sprite_set: gfx::SpriteSet,
+        width: i32,
+        height: i32,
+    ) -> Self {
+        let mut instances = graphics::InstanceArray::new(ctx, sprite_set.img.clone());
+        // mapsize + log panel + 50 entities
+        instances.resize(ctx, (width * (height + log::LOG_PANEL_HEIGHT)) as u32 + 50);
+
+        Game {
+            instances,
+            sprite_set,
+            width,
+            height,
+            sim: Simulation::new(state),
+            replay: ReplayMode::Off,
+            quit: false,
+            dt: time::Duration::default(),
+        }
+    }
+
+    /// Records every action the hero takes into replay
+    pub fn record(&mut self, replay: Replay) {
+        self.replay = ReplayMode::Recording(replay);
+    }
+
+    /// Performs the actions in replay instead of reading the keyboard, the
+    /// state must be the one the replay started from
+    pub fn play(&mut self, replay: Replay) {
+        self.replay = ReplayMode::Playing { replay, next: 0 };
+    }
+
+    fn next_action(&mut self, state: &mut GameState) -> Option<Action> {
+        if let ReplayMode::Playing { replay, next } = &mut self.replay {
+            // One recorded action per update
+            if let Some(action) = replay.action(*next, state) {
+                *next += 1;
+                return Some(action);
+            }
+            let (color, text) = if *next < replay.len() {
+                (
+                    gfx::RED_BRIGHT,
+                    format!("Replay no longer matches the game at action {}.", *next),
+                )
+            } else if replay.verify(state) {
+                (
+                    gfx::GREEN_BRIGHT,
+                    "Replay finished, the game ended up as recorded.".to_string(),
+                )
+            } else {
+                (
+                    gfx::RED_BRIGHT,
+                    "Replay finished, but the game ended up differently!".to_string(),
+                )
+            };
+            state.log.add(color, text);
+            // Let the player take over from here
+            self.replay = ReplayMode::Off;
+            return None;
+        }
+
+        // After death only leaving the game is left, which is not an action
+        if state.hero_dead() {
+            state.pending_action = None;
+            return None;
+        }
+        let action = state
+            .pending_action
+            .take()
+            .or_else(|| input_handler(&state.input, &state.world, state.hero));
+        if let (ReplayMode::Recording(replay), Some(action)) = (&mut self.replay, action) {
+            replay.record(state, action);
+        }
+        action
+    }
+
+    fn save_replay(&mut self, ctx: &Context, state: &mut GameState) {
+        if let ReplayMode::Recording(replay) = &mut self.replay {
+            replay.finish(state);
+            if let Err(e) = replay.save(&replay_path(ctx)) {
+                state
+                    .log
+                    .add(gfx::RED_BRIGHT, format!("Saving replay failed: {}", e));
+            }
+        }
+    }
+}
+
+impl Scene<GameState> for Game {
+    fn update(&mut self, ctx: &mut Context, state: &mut GameState) -> Transition<GameState> {
+        if self.quit {
+            self.save_replay(ctx, state);
+            return Transition::Pop;
+        }
+
+        let action = self.next_action(state);
+        let alive = !state.hero_dead();
+        if self.sim.step(state, action) {
+            if let Err(e) = state.save(&save_path(ctx)) {
+                state
+                    .log
+                    .add(gfx::RED_BRIGHT, format!("Autosave failed: {}", e));
+            }
+            self.save_replay(ctx, state);
+        }
+        if alive && state.hero_dead() {
+            // Nothing more gets recorded, the replay ends with the hero's death
+            self.save_replay(ctx, state);
+        }
+        if state.input.key == Some(KeyCode::S) {
+            match state.save(&save_path(ctx)) {
+                Ok(()) => state.log.add(gfx::WHITE, "Game saved."),
+                Err(e) => state
+                    .log
+                    .add(gfx::RED_BRIGHT, format!("Saving failed: {}", e)),
+            }
+            self.save_replay(ctx, state);
+        }
+
         self.dt += ctx.time.delta();
         if self.dt > time::Duration::new(0, 100000000) {
-            explosion_handler(&mut state.world);
+            self.sim.animate(state);
             self.dt = time::Duration::default();
         }
 
@@ -179,11 +708,13 @@ impl Scene<GameState> for Game {
                     let spr = match t {
                         Tile::Floor => gfx::CP437::ChDot,
                         Tile::StairUp => gfx::CP437::LessThan,
+                        Tile::StairDown => gfx::CP437::GreaterThan,
+                        Tile::Door => gfx::CP437::Plus,
                         _ => gfx::CP437::Pillar,
                     };
                     let mut draw = graphics::DrawParam::new()
                         .dest(d * 12.)
-                        .src(state.sprite_set.src(spr));
+                        .src(self.sprite_set.src(spr));
                     if explored && !in_los {
                         draw.color.a *= 0.2;
                     }
@@ -207,55 +738,528 @@ impl Scene<GameState> for Game {
                 self.instances.push(
                     graphics::DrawParam::new()
                         .dest(d * 12.)
-                        .src(state.sprite_set.src(renderable.spr))
+                        .src(self.sprite_set.src(renderable.spr))
                         .color(renderable.color),
                 );
             }
         }
-        let scale = Vec2::splat(
-            (canvas.scissor_rect().w / (self.width as f32 * 12.))
-                .min(canvas.scissor_rect().h / (self.height as f32 * 12.)),
-        );
-        canvas.draw(
-            &self.instances,
-            graphics::DrawParam::new()
-                .dest(Vec2::new(
-                    (canvas.scissor_rect().w - (self.width as f32 * 12.) * scale.x) / 2.,
-                    (canvas.scissor_rect().h - (self.height as f32 * 12.) * scale.y) / 2.,
-                ))
-                .scale(scale),
+        log::draw_panel(
+            &mut self.instances,
+            &self.sprite_set,
+            &state.log,
+            self.width,
+            self.height,
+        );
+        let (offset, scale) = map_offset_and_scale(canvas.scissor_rect(), self.width, self.height);
+        canvas.draw(
+            &self.instances,
+            graphics::DrawParam::new().dest(offset).scale(scale),
+        );
+
+        canvas.finish(ctx)
+    }
+
+    fn key_down(&mut self, input: KeyInput, _repeat: bool) -> Transition<GameState> {
+        match input.keycode {
+            Some(KeyCode::Escape) => {
+                // Leave in update, where the replay can be written
+                self.quit = true;
+                Transition::None
+            }
+            Some(KeyCode::I) => Transition::Push(Box::new(Inventory::new(self.sprite_set.clone()))),
+            Some(KeyCode::M) => Transition::Push(Box::new(log::MessageHistory::new(
+                self.sprite_set.clone(),
+                self.width,
+                self.height,
+            ))),
+            _ => Transition::None,
+        }
+    }
+}
+
+/// Overlay listing the hero's items, each selected by a letter
+struct Inventory {
+    sprite_set: gfx::SpriteSet,
+    instances: Option<graphics::InstanceArray>,
+    items: Vec<(hecs::Entity, String)>,
+    selected: Option<usize>,
+    command: Option<KeyCode>,
+}
+
+impl Inventory {
+    fn new(sprite_set: gfx::SpriteSet) -> Self {
+        Inventory {
+            sprite_set,
+            instances: None,
+            items: vec![],
+            selected: None,
+            command: None,
+        }
+    }
+}
+
+impl Scene<GameState> for Inventory {
+    fn update(&mut self, _ctx: &mut Context, state: &mut GameState) -> Transition<GameState> {
+        self.items = backpack(&state.world, state.hero)
+            .into_iter()
+            .map(|e| {
+                let equipped = state.world.satisfies::<&Equipped>(e).unwrap_or(false);
+                let name = name_of(&state.world, e);
+                (e, if equipped { name + " (equipped)" } else { name })
+            })
+            .collect();
+
+        let item = match self.selected.and_then(|i| self.items.get(i)) {
+            Some((item, _)) => *item,
+            None => {
+                self.selected = None;
+                return Transition::None;
+            }
+        };
+        let radius = state
+            .world
+            .get::<&Explosive>(item)
+            .map_or(0, |e| e.radius as i32);
+        let action = match self.command.take() {
+            Some(KeyCode::U) => match state.world.get::<&Ranged>(item) {
+                Ok(r) => {
+                    let targeting = Targeting::new(item, TargetMode::Zap, r.range, radius);
+                    return Transition::Replace(Box::new(targeting));
+                }
+                Err(_) if usable(&state.world, item) => Action::Use(item),
+                Err(_) => {
+                    state.log.add(gfx::WHITE, "You can't use that.");
+                    return Transition::None;
+                }
+            },
+            Some(KeyCode::D) => Action::Drop(item),
+            Some(KeyCode::T) => {
+                let targeting = Targeting::new(item, TargetMode::Throw, THROW_RANGE, radius);
+                return Transition::Replace(Box::new(targeting));
+            }
+            _ => return Transition::None,
+        };
+        state.pending_action = Some(action);
+        Transition::Pop
+    }
+
+    fn draw(&mut self, ctx: &mut Context, state: &mut GameState) -> GameResult {
+        let mut canvas = graphics::Canvas::from_frame(ctx, None);
+        canvas.set_sampler(graphics::Sampler::nearest_clamp());
+
+        let mut lines = vec!["{white_bright}Inventory".to_string()];
+        if self.items.is_empty() {
+            lines.push("You are not carrying anything.".to_string());
+        }
+        for (i, ((_, name), hotkey)) in self.items.iter().zip(ITEM_HOTKEYS.chars()).enumerate() {
+            if self.selected == Some(i) {
+                lines.push(format!("{}) {{yellow_bright}}{}", hotkey, name));
+            } else {
+                lines.push(format!("{}) {}", hotkey, name));
+            }
+        }
+        if self.selected.is_some() {
+            lines.push("{cyan}[u]se, [d]rop or [t]hrow".to_string());
+        }
+
+        let instances = self
+            .instances
+            .get_or_insert_with(|| graphics::InstanceArray::new(ctx, self.sprite_set.img.clone()));
+        instances.clear();
+        let panel = Box2D::new(pt(2, 2), pt(42, 4 + lines.len() as i32));
+        text::fill(instances, &self.sprite_set, panel, gfx::BLACK);
+        for (i, line) in lines.iter().enumerate() {
+            let row = Box2D::new(
+                pt(panel.min.x + 2, panel.min.y + 1 + i as i32),
+                pt(panel.max.x - 2, panel.min.y + 2 + i as i32),
+            );
+            text::draw(instances, &self.sprite_set, row, line, gfx::WHITE, None);
+        }
+
+        let (offset, scale) = map_offset_and_scale(
+            canvas.scissor_rect(),
+            state.map.tiles.width,
+            state.map.tiles.height,
+        );
+        canvas.draw(
+            &*instances,
+            graphics::DrawParam::new().dest(offset).scale(scale),
+        );
+
+        canvas.finish(ctx)
+    }
+
+    fn key_down(&mut self, input: KeyInput, _repeat: bool) -> Transition<GameState> {
+        match input.keycode {
+            Some(KeyCode::Escape) if self.selected.is_some() => self.selected = None,
+            Some(KeyCode::Escape) => return Transition::Pop,
+            Some(k @ (KeyCode::U | KeyCode::D | KeyCode::T)) if self.selected.is_some() => {
+                self.command = Some(k)
+            }
+            Some(k) if (KeyCode::A as u32..=KeyCode::Z as u32).contains(&(k as u32)) => {
+                let letter = (b'a' + (k as u32 - KeyCode::A as u32) as u8) as char;
+                if let Some(i) = ITEM_HOTKEYS.find(letter) {
+                    self.selected = Some(i);
+                }
+            }
+            _ => (),
+        }
+        Transition::None
+    }
+
+    fn draw_previous(&self) -> bool {
+        true
+    }
+}
+
+/// Whether using item does anything, items with a range are used by zapping
+/// them at a target instead
+fn usable(world: &hecs::World, item: hecs::Entity) -> bool {
+    world.satisfies::<&ProvidesHealing>(item).unwrap_or(false)
+        || world.satisfies::<&Equippable>(item).unwrap_or(false)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TargetMode {
+    Throw,
+    Zap,
+}
+
+/// Overlay for picking a visible tile to throw or zap an item at
+struct Targeting {
+    item: hecs::Entity,
+    mode: TargetMode,
+    range: i32,
+    radius: i32,
+    cursor: Option<Point>,
+    step: Option<Point>,
+    confirmed: bool,
+    path: Vec<Point>,
+    blast: Vec<Point>,
+}
+
+impl Targeting {
+    fn new(item: hecs::Entity, mode: TargetMode, range: i32, radius: i32) -> Self {
+        Targeting {
+            item,
+            mode,
+            range,
+            radius,
+            cursor: None,
+            step: None,
+            confirmed: false,
+            path: vec![],
+            blast: vec![],
+        }
+    }
+}
+
+impl Scene<GameState> for Targeting {
+    fn update(&mut self, _ctx: &mut Context, state: &mut GameState) -> Transition<GameState> {
+        let origin = match state.world.get::<&Position>(state.hero) {
+            Ok(pos) => pos.0,
+            Err(_) => return Transition::Pop,
+        };
+        let range = self.range;
+        let cursor = *self.cursor.get_or_insert_with(|| {
+            nearest_visible_enemy(&state.world, state.hero)
+                .filter(|p| Movement::EightWay.distance(origin, *p) <= range)
+                .unwrap_or(origin)
+        });
+
+        // Only move the cursor within range over tiles the hero can see
+        if let Some(step) = self.step.take() {
+            let n = cursor + step.to_vector();
+            let visible = state
+                .world
+                .get::<&Viewshed>(state.hero)
+                .is_ok_and(|v| v.visible_tiles.contains(&n));
+            if visible && Movement::EightWay.distance(origin, n) <= self.range {
+                self.cursor = Some(n);
+            }
+        }
+
+        let target = self.cursor.unwrap_or(origin);
+        self.path = projectile_path(&state.map, origin, target);
+        let impact = *self.path.last().unwrap_or(&origin);
+        self.blast = if self.radius > 0 {
+            blast_area(&state.map, impact, self.radius)
+        } else {
+            vec![impact]
+        };
+
+        if self.confirmed {
+            self.confirmed = false;
+            if Movement::EightWay.distance(origin, target) > self.range {
+                return Transition::None;
+            }
+            state.pending_action = Some(match self.mode {
+                TargetMode::Throw => Action::Throw(self.item, impact),
+                TargetMode::Zap => Action::Zap(self.item, impact),
+            });
+            return Transition::Pop;
+        }
+        Transition::None
+    }
+
+    fn draw(&mut self, ctx: &mut Context, state: &mut GameState) -> GameResult {
+        let mut canvas = graphics::Canvas::from_frame(ctx, None);
+
+        let (offset, scale) = map_offset_and_scale(
+            canvas.scissor_rect(),
+            state.map.tiles.width,
+            state.map.tiles.height,
         );
+        let mut highlight = |p: Point, mut color: graphics::Color| {
+            color.a = 0.4;
+            canvas.draw(
+                &graphics::Quad,
+                graphics::DrawParam::new()
+                    .dest_rect(graphics::Rect::new(
+                        offset.x + p.x as f32 * 12. * scale.x,
+                        offset.y + p.y as f32 * 12. * scale.y,
+                        12. * scale.x,
+                        12. * scale.y,
+                    ))
+                    .color(color),
+            );
+        };
+        for p in &self.blast {
+            highlight(*p, gfx::RED);
+        }
+        for p in self.path.iter().skip(1) {
+            highlight(*p, gfx::YELLOW_BRIGHT);
+        }
+        if let Some(cursor) = self.cursor {
+            highlight(cursor, gfx::WHITE_BRIGHT);
+        }
 
         canvas.finish(ctx)
     }
 
     fn key_down(&mut self, input: KeyInput, _repeat: bool) -> Transition<GameState> {
         match input.keycode {
-            Some(KeyCode::Escape) => Transition::Pop,
-            _ => Transition::None,
+            Some(KeyCode::Escape) => return Transition::Pop,
+            Some(KeyCode::Return) => self.confirmed = true,
+            Some(KeyCode::Up) => self.step = Some(pt(0, -1)),
+            Some(KeyCode::Down) => self.step = Some(pt(0, 1)),
+            Some(KeyCode::Left) => self.step = Some(pt(-1, 0)),
+            Some(KeyCode::Right) => self.step = Some(pt(1, 0)),
+            _ => (),
         }
+        Transition::None
+    }
+
+    fn draw_previous(&self) -> bool {
+        true
     }
 }
 
-fn input_handler(input: &KeyState, hero: hecs::Entity, chan: &mut EventChan) -> bool {
+/// Position and scale that fit a map of size, and the log panel below it, on screen
+fn map_offset_and_scale(screen: graphics::Rect, width: i32, height: i32) -> (Vec2, Vec2) {
+    let height = height + log::LOG_PANEL_HEIGHT;
+    let scale =
+        Vec2::splat((screen.w / (width as f32 * 12.)).min(screen.h / (height as f32 * 12.)));
+    let offset = Vec2::new(
+        (screen.w - (width as f32 * 12.) * scale.x) / 2.,
+        (screen.h - (height as f32 * 12.) * scale.y) / 2.,
+    );
+    (offset, scale)
+}
+
+fn input_handler(input: &KeyState, world: &hecs::World, hero: hecs::Entity) -> Option<Action> {
+    let shift = input.mods.is_some_and(|m| m.contains(KeyMods::SHIFT));
     match input.key {
-        Some(KeyCode::Up) => chan.single_write(Event::Move(hero, pt(0, -1))),
-        Some(KeyCode::Down) => chan.single_write(Event::Move(hero, pt(0, 1))),
-        Some(KeyCode::Left) => chan.single_write(Event::Move(hero, pt(-1, 0))),
-        Some(KeyCode::Right) => chan.single_write(Event::Move(hero, pt(1, 0))),
-        Some(KeyCode::Period) => (),
-        _ => return false,
-    };
-    true
+        Some(KeyCode::Up) => Some(Action::Move(pt(0, -1))),
+        Some(KeyCode::Down) => Some(Action::Move(pt(0, 1))),
+        Some(KeyCode::Left) => Some(Action::Move(pt(-1, 0))),
+        Some(KeyCode::Right) => Some(Action::Move(pt(1, 0))),
+        Some(KeyCode::Period) if shift => Some(Action::Descend),
+        Some(KeyCode::Comma) if shift => Some(Action::Ascend),
+        Some(KeyCode::Period) => Some(Action::Wait),
+        Some(KeyCode::G) => Some(Action::PickUp),
+        Some(KeyCode::D) => backpack(world, hero).last().map(|item| Action::Drop(*item)),
+        _ => None,
+    }
 }
 
-fn ai_handler(world: &hecs::World, chan: &mut EventChan) {
-    world
-        .query::<()>()
-        .with::<&AI>()
+fn action_handler(action: Action, hero: hecs::Entity, chan: &mut EventChan) {
+    match action {
+        Action::Move(d) => chan.single_write(Event::Move(hero, d)),
+        Action::Wait => (),
+        Action::PickUp => chan.single_write(Event::PickUp(hero)),
+        Action::Drop(item) => chan.single_write(Event::Drop(hero, item)),
+        Action::Use(item) => chan.single_write(Event::Use(hero, item)),
+        Action::Throw(item, target) => chan.single_write(Event::Throw(hero, item, target)),
+        Action::Zap(item, target) => chan.single_write(Event::Zap(hero, item, target)),
+        Action::Descend => chan.single_write(Event::Descend(hero)),
+        Action::Ascend => chan.single_write(Event::Ascend(hero)),
+    }
+}
+
+/// Tiles a projectile passes from origin towards target, stopping in front of
+/// walls and on the first tile blocked by something else
+fn projectile_path(map: &Map, origin: Point, target: Point) -> Vec<Point> {
+    let mut path = vec![];
+    for p in line(origin, target) {
+        if !map.tiles.in_bounds(p) || map.tiles[p].blocked() {
+            break;
+        }
+        path.push(p);
+        if p != origin && map.blocked[p] {
+            break;
+        }
+    }
+    path
+}
+
+/// Tiles covered by an explosion, walls shield whatever is behind them
+fn blast_area(map: &Map, center: Point, radius: i32) -> Vec<Point> {
+    let opaque_at = |p: Point| map.tiles.in_bounds(p) && map.tiles[p].opaque();
+    let mut area: Vec<Point> = fov::calculate(center, radius, opaque_at)
+        .into_iter()
+        .filter(|p| map.tiles.in_bounds(*p) && !map.tiles[*p].opaque())
+        .collect();
+    // Sets iterate in random order, keep blasts reproducible
+    area.sort_by_key(|p| (p.y, p.x));
+    area
+}
+
+/// Detonates the explosive source at center along with any other explosives
+/// caught in the blast
+fn explode(
+    world: &hecs::World,
+    map: &Map,
+    source: hecs::Entity,
+    center: Point,
+    cmd: &mut hecs::CommandBuffer,
+    events: &mut Vec<Event>,
+) {
+    let mut pending = vec![(source, center)];
+    let mut detonated = HashSet::new();
+    while let Some((e, center)) = pending.pop() {
+        let explosive = match world.get::<&Explosive>(e) {
+            Ok(explosive) => *explosive,
+            Err(_) => continue,
+        };
+        if !detonated.insert(e) {
+            continue;
+        }
+        cmd.despawn(e);
+
+        for n in blast_area(map, center, explosive.radius as i32) {
+            cmd.spawn((
+                Explosion { duration_left: 6 },
+                Position(n),
+                Renderable {
+                    spr: gfx::CP437::Filled3,
+                    color: gfx::RED,
+                },
+            ));
+            let dmg = explosive.damage_at(center.to_f32().distance_to(n.to_f32()));
+            for other in &map.entities[n] {
+                if let Ok(true) = world.satisfies::<&Explosive>(*other) {
+                    pending.push((*other, n));
+                } else {
+                    events.push(Event::TakeDamage(*other, dmg));
+                }
+            }
+        }
+    }
+}
+
+fn nearest_visible_enemy(world: &hecs::World, hero: hecs::Entity) -> Option<Point> {
+    let pos = world.get::<&Position>(hero).ok()?.0;
+    let viewshed = world.get::<&Viewshed>(hero).ok()?;
+    let mut enemies = world.query::<&Position>().with::<&AI>();
+    enemies
         .iter()
-        .map(|(e, _)| Event::Move(e, pt(-1, 0)))
-        .for_each(|e| chan.single_write(e));
+        .map(|(_, p)| p.0)
+        .filter(|p| viewshed.visible_tiles.contains(p))
+        .min_by_key(|p| Movement::EightWay.distance(pos, *p))
+}
+
+fn ai_handler(
+    world: &mut hecs::World,
+    map: &Map,
+    hero: hecs::Entity,
+    chan: &mut EventChan,
+    rng: &mut impl Rng,
+) {
+    let target = match world.get::<&Position>(hero) {
+        Ok(pos) => pos.0,
+        Err(_) => return,
+    };
+    let mut flee_map: Option<DijkstraMap> = None;
+    let mut moves: Vec<Event> = vec![];
+    for (e, (ai, pos, viewshed, stats, coward)) in world.query_mut::<(
+        &mut AI,
+        &Position,
+        Option<&Viewshed>,
+        Option<&CombatStats>,
+        Option<&Coward>,
+    )>() {
+        let sees_target = viewshed.is_some_and(|v| v.visible_tiles.contains(&target));
+        let range = viewshed.map_or(0, |v| v.range);
+        let afraid = coward.is_some() || stats.is_some_and(|s| s.hp * 4 <= s.max_hp);
+        if sees_target {
+            ai.state = if afraid {
+                AIState::Fleeing { from: target }
+            } else {
+                AIState::Hunting { last_seen: target }
+            };
+        }
+
+        let step = match ai.state {
+            AIState::Idle => {
+                if rng.gen_ratio(1, 5) {
+                    ai.state = AIState::Wandering;
+                }
+                None
+            }
+            AIState::Wandering => {
+                if rng.gen_ratio(1, 10) {
+                    ai.state = AIState::Idle;
+                }
+                Movement::FourWay
+                    .directions()
+                    .choose(rng)
+                    .map(|d| pos.0 + d.to_vector())
+                    .filter(|n| map.blocked.in_bounds(*n) && !map.blocked[*n])
+            }
+            AIState::Hunting { last_seen } => {
+                // Head for where the target was last seen and give up once there
+                let next = pathfinding::astar(&map.blocked, pos.0, last_seen, Movement::FourWay)
+                    .and_then(|path| path.first().copied());
+                if next.is_none() || (next == Some(last_seen) && !sees_target) {
+                    ai.state = AIState::Wandering;
+                }
+                next
+            }
+            AIState::Fleeing { from } => {
+                if !sees_target && Movement::FourWay.distance(pos.0, from) > range {
+                    ai.state = AIState::Idle;
+                }
+                // Every fleeing monster shares the map built from the hero's position
+                if from == target {
+                    flee_map
+                        .get_or_insert_with(|| {
+                            DijkstraMap::new(&map.blocked, &[target], Movement::FourWay)
+                                .inverted(&map.blocked, -1.2)
+                        })
+                        .downhill(pos.0)
+                } else {
+                    DijkstraMap::new(&map.blocked, &[from], Movement::FourWay)
+                        .inverted(&map.blocked, -1.2)
+                        .downhill(pos.0)
+                }
+            }
+        };
+        if let Some(n) = step {
+            moves.push(Event::Move(e, n - pos.0.to_vector()));
+        }
+    }
+    chan.drain_vec_write(&mut moves);
 }
 
 fn move_handler(
@@ -328,54 +1332,329 @@ fn collision_handler(
     map: &Map,
     chan: &mut EventChan,
     r: &mut shrev::ReaderId<Event>,
+    log: &mut MessageLog,
 ) {
     let mut events: Vec<Event> = vec![];
     for ev in chan.read(r) {
         if let Event::Collision(a, b) = ev {
-            {
-                let mut e = world.query_one::<&Name>(*a).unwrap();
-                let mut other = world.query_one::<&Name>(*b).unwrap();
-                println!(
-                    "{} collided with {}.",
-                    e.get().unwrap_or(&Name("unnamed".to_string())).0,
-                    other.get().unwrap_or(&Name("unnamed".to_string())).0
-                );
+            // Player and AI attack each other
+            let attacks = |a: hecs::Entity, b: hecs::Entity| {
+                matches!(
+                    (world.satisfies::<&Player>(a), world.satisfies::<&AI>(b)),
+                    (Ok(true), Ok(true))
+                )
+            };
+            // Fleeing AI only tries to get away, even when bumping into the player
+            let fleeing = matches!(
+                world.get::<&AI>(*a).map(|ai| ai.state),
+                Ok(AIState::Fleeing { .. })
+            );
+            if attacks(*a, *b) || (attacks(*b, *a) && !fleeing) {
+                if let Some(dmg) = melee_damage(world, *a, *b) {
+                    let color = if world.satisfies::<&Player>(*a).unwrap_or(false) {
+                        gfx::WHITE
+                    } else {
+                        gfx::RED
+                    };
+                    let text = if dmg > 0 {
+                        format!(
+                            "{} hits {} for {} damage.",
+                            name_of(world, *a),
+                            name_of(world, *b),
+                            dmg
+                        )
+                    } else {
+                        format!("{} misses {}.", name_of(world, *a), name_of(world, *b))
+                    };
+                    log.add(color, text);
+                    events.push(Event::TakeDamage(*b, dmg));
+                }
             }
 
-            // Player attacks AI
-            if let Ok(true) = world.satisfies::<&Player>(*a) {
-                if let Ok(true) = world.satisfies::<&AI>(*b) {
-                    events.push(Event::TakeDamage(*b, 1));
+            // Colliding with an Explosive trap sets it off, items have to be thrown
+            let mut cmd = hecs::CommandBuffer::new();
+            if let Ok(false) = world.satisfies::<&Item>(*b) {
+                if let Ok(pos) = world.get::<&Position>(*b).map(|p| p.0) {
+                    explode(world, map, *b, pos, &mut cmd, &mut events);
                 }
             }
+            cmd.run_on(world);
+        }
+    }
+    chan.drain_vec_write(&mut events);
+}
 
-            // Colliding with Explosive sets it off
-            let mut cmd = hecs::CommandBuffer::new();
-            if let Ok(pos) = world.query_one_mut::<hecs::With<&Position, &Explosive>>(*b) {
-                for p in [pt(0, 0), pt(1, 0), pt(-1, 0), pt(0, -1), pt(0, 1)] {
-                    let n = pos.0 + p.to_vector();
-                    cmd.spawn((
-                        Explosion { duration_left: 6 },
-                        Position(n),
-                        Renderable {
-                            spr: gfx::CP437::Filled3,
-                            color: gfx::RED,
-                        },
-                    ));
-                    for other in &map.entities[n] {
-                        if other != b {
-                            events.push(Event::TakeDamage(*other, 1))
-                        }
+fn item_handler(
+    world: &mut hecs::World,
+    map: &Map,
+    chan: &mut EventChan,
+    r: &mut shrev::ReaderId<Event>,
+    log: &mut MessageLog,
+) {
+    let mut events: Vec<Event> = vec![];
+    for ev in chan.read(r) {
+        match ev {
+            Event::PickUp(owner) => {
+                let pos = match world.get::<&Position>(*owner) {
+                    Ok(pos) => pos.0,
+                    Err(_) => continue,
+                };
+                let item = map.entities[pos]
+                    .iter()
+                    .find(|e| matches!(world.satisfies::<&Item>(**e), Ok(true)));
+                if let Some(item) = item {
+                    if backpack(world, *owner).len() >= BACKPACK_CAPACITY {
+                        log.add(
+                            gfx::YELLOW,
+                            format!("{} can't carry any more.", name_of(world, *owner)),
+                        );
+                        continue;
                     }
+                    world
+                        .remove_one::<Position>(*item)
+                        .expect("item without position");
+                    world
+                        .insert_one(*item, InBackpack { owner: *owner })
+                        .expect("item entity missing");
+                    events.push(Event::PickedUp(*owner, *item));
                 }
-                cmd.despawn(*b);
             }
-            cmd.run_on(world);
+            Event::Drop(owner, item) => {
+                match world.get::<&InBackpack>(*item) {
+                    Ok(b) if b.owner == *owner => (),
+                    _ => continue,
+                }
+                let pos = match world.get::<&Position>(*owner) {
+                    Ok(pos) => pos.0,
+                    Err(_) => continue,
+                };
+                world
+                    .remove_one::<InBackpack>(*item)
+                    .expect("item not in backpack");
+                let _ = world.remove_one::<Equipped>(*item);
+                world
+                    .insert_one(*item, Position(pos))
+                    .expect("item entity missing");
+                events.push(Event::Dropped(*owner, *item));
+            }
+            Event::Use(owner, item) => {
+                match world.get::<&InBackpack>(*item) {
+                    Ok(b) if b.owner == *owner => (),
+                    _ => continue,
+                }
+                if let Ok(amount) = world.get::<&ProvidesHealing>(*item).map(|h| h.amount) {
+                    match world.query_one_mut::<&mut CombatStats>(*owner) {
+                        // No potion brings back the dead
+                        Ok(stats) if stats.hp <= 0 => continue,
+                        Ok(stats) => stats.hp = (stats.hp + amount).min(stats.max_hp),
+                        Err(_) => (),
+                    }
+                    log.add(
+                        gfx::WHITE,
+                        format!(
+                            "{} drinks {}.",
+                            name_of(world, *owner),
+                            name_of(world, *item)
+                        ),
+                    );
+                    world.despawn(*item).expect("failed to despawn entity");
+                } else if let Ok(slot) = world.get::<&Equippable>(*item).map(|eq| eq.slot) {
+                    if world.remove_one::<Equipped>(*item).is_ok() {
+                        log.add(
+                            gfx::WHITE,
+                            format!(
+                                "{} unequips {}.",
+                                name_of(world, *owner),
+                                name_of(world, *item)
+                            ),
+                        );
+                        continue;
+                    }
+                    let worn: Vec<hecs::Entity> = world
+                        .query::<&Equipped>()
+                        .iter()
+                        .filter(|(_, eq)| eq.owner == *owner && eq.slot == slot)
+                        .map(|(e, _)| e)
+                        .collect();
+                    for e in worn {
+                        world.remove_one::<Equipped>(e).expect("item not equipped");
+                    }
+                    world
+                        .insert_one(
+                            *item,
+                            Equipped {
+                                owner: *owner,
+                                slot,
+                            },
+                        )
+                        .expect("item entity missing");
+                    log.add(
+                        gfx::WHITE,
+                        format!(
+                            "{} equips {}.",
+                            name_of(world, *owner),
+                            name_of(world, *item)
+                        ),
+                    );
+                }
+            }
+            Event::Throw(owner, item, target) => {
+                match world.get::<&InBackpack>(*item) {
+                    Ok(b) if b.owner == *owner => (),
+                    _ => continue,
+                }
+                world
+                    .remove_one::<InBackpack>(*item)
+                    .expect("item not in backpack");
+                let _ = world.remove_one::<Equipped>(*item);
+                world
+                    .insert_one(*item, Position(*target))
+                    .expect("item entity missing");
+                log.add(
+                    gfx::WHITE,
+                    format!(
+                        "{} throws {}.",
+                        name_of(world, *owner),
+                        name_of(world, *item)
+                    ),
+                );
+                if !resolve_item_effect(world, map, *item, *target, &mut events) {
+                    for other in &map.entities[*target] {
+                        events.push(Event::TakeDamage(*other, THROWN_DAMAGE));
+                    }
+                }
+            }
+            Event::Zap(owner, item, target) => {
+                match world.get::<&InBackpack>(*item) {
+                    Ok(b) if b.owner == *owner => (),
+                    _ => continue,
+                }
+                log.add(
+                    gfx::WHITE,
+                    format!("{} uses {}.", name_of(world, *owner), name_of(world, *item)),
+                );
+                resolve_item_effect(world, map, *item, *target, &mut events);
+                if world.contains(*item) {
+                    world.despawn(*item).expect("failed to despawn entity");
+                }
+            }
+            Event::PickedUp(owner, item) => log.add(
+                gfx::WHITE,
+                format!(
+                    "{} picks up {}.",
+                    name_of(world, *owner),
+                    name_of(world, *item)
+                ),
+            ),
+            Event::Dropped(owner, item) => log.add(
+                gfx::WHITE,
+                format!(
+                    "{} drops {}.",
+                    name_of(world, *owner),
+                    name_of(world, *item)
+                ),
+            ),
+            _ => (),
         }
     }
     chan.drain_vec_write(&mut events);
 }
 
+/// Applies the damaging effects of an item at target, consuming explosives.
+/// Returns false if the item has no such effects.
+fn resolve_item_effect(
+    world: &mut hecs::World,
+    map: &Map,
+    item: hecs::Entity,
+    target: Point,
+    events: &mut Vec<Event>,
+) -> bool {
+    let mut resolved = false;
+    if let Ok(amount) = world.get::<&InflictsDamage>(item).map(|d| d.amount) {
+        for other in &map.entities[target] {
+            events.push(Event::TakeDamage(*other, amount));
+        }
+        resolved = true;
+    }
+    let mut cmd = hecs::CommandBuffer::new();
+    if let Ok(true) = world.satisfies::<&Explosive>(item) {
+        explode(world, map, item, target, &mut cmd, events);
+        resolved = true;
+    }
+    cmd.run_on(world);
+    resolved
+}
+
+/// Items carried by owner, in a stable order
+fn backpack(world: &hecs::World, owner: hecs::Entity) -> Vec<hecs::Entity> {
+    let mut items: Vec<hecs::Entity> = world
+        .query::<&InBackpack>()
+        .iter()
+        .filter(|(_, b)| b.owner == owner)
+        .map(|(e, _)| e)
+        .collect();
+    items.sort_by_key(|e| e.id());
+    items
+}
+
+/// Summed (power, defense) of everything owner has equipped
+fn equipment_bonus(world: &hecs::World, owner: hecs::Entity) -> (i32, i32) {
+    world
+        .query::<(&Equipped, &Equippable)>()
+        .iter()
+        .filter(|(_, (eq, _))| eq.owner == owner)
+        .fold((0, 0), |(p, d), (_, (_, item))| {
+            (p + item.power, d + item.defense)
+        })
+}
+
+fn melee_damage(world: &hecs::World, attacker: hecs::Entity, target: hecs::Entity) -> Option<i32> {
+    let power =
+        world.get::<&CombatStats>(attacker).ok()?.power + equipment_bonus(world, attacker).0;
+    let defense =
+        world.get::<&CombatStats>(target).ok()?.defense + equipment_bonus(world, target).1;
+    Some((power - defense).max(0))
+}
+
+fn name_of(world: &hecs::World, e: hecs::Entity) -> String {
+    world
+        .get::<&Name>(e)
+        .map_or("unnamed".to_string(), |n| n.0.clone())
+}
+
+/// Returns the depth to move to when the hero takes a staircase
+fn stairs_handler(
+    world: &hecs::World,
+    map: &Map,
+    depth: usize,
+    chan: &EventChan,
+    r: &mut shrev::ReaderId<Event>,
+    log: &mut MessageLog,
+) -> Option<usize> {
+    let mut target = None;
+    for ev in chan.read(r) {
+        let (e, tile) = match ev {
+            Event::Descend(e) => (*e, Tile::StairDown),
+            Event::Ascend(e) => (*e, Tile::StairUp),
+            _ => continue,
+        };
+        let pos = match world.get::<&Position>(e) {
+            Ok(pos) => pos.0,
+            Err(_) => continue,
+        };
+        if map.tiles[pos] != tile {
+            log.add(gfx::WHITE, "There are no stairs here.");
+            continue;
+        }
+        match tile {
+            Tile::StairDown => target = Some(depth + 1),
+            _ if depth == 0 => log.add(gfx::WHITE, "The way back up is blocked."),
+            _ => target = Some(depth - 1),
+        }
+    }
+    target
+}
+
 fn explosion_handler(world: &mut hecs::World) {
     let mut cmd = hecs::CommandBuffer::new();
     for (e, (exp, rd)) in world.query_mut::<(&mut Explosion, &mut Renderable)>() {
@@ -386,7 +1665,7 @@ fn explosion_handler(world: &mut hecs::World) {
             1 => {
                 rd.spr = gfx::CP437::Filled1;
                 rd.color = gfx::YELLOW_BRIGHT;
-            },
+            }
             0 => cmd.despawn(e),
             _ => (),
         }
@@ -394,9 +1673,59 @@ fn explosion_handler(world: &mut hecs::World) {
     cmd.run_on(world);
 }
 
-fn damage_handler(world: &mut hecs::World, chan: &EventChan, r: &mut shrev::ReaderId<Event>) {
+fn clear_explosions(world: &mut hecs::World) {
+    let explosions: Vec<hecs::Entity> = world
+        .query_mut::<&Explosion>()
+        .into_iter()
+        .map(|(e, _)| e)
+        .collect();
+    for e in explosions {
+        world.despawn(e).expect("failed to despawn entity");
+    }
+}
+
+fn damage_handler(
+    world: &mut hecs::World,
+    chan: &mut EventChan,
+    r: &mut shrev::ReaderId<Event>,
+    log: &mut MessageLog,
+) {
+    let mut deaths: Vec<Event> = vec![];
+    for ev in chan.read(r) {
+        if let Event::TakeDamage(e, dmg) = ev {
+            // Entities without stats, or already dead ones, ignore damage
+            if let Ok(stats) = world.query_one_mut::<&mut CombatStats>(*e) {
+                if stats.hp <= 0 {
+                    continue;
+                }
+                stats.hp -= dmg;
+                if stats.hp <= 0 {
+                    deaths.push(Event::Died(*e));
+                    log.add(gfx::RED_BRIGHT, format!("{} dies.", name_of(world, *e)));
+                }
+            }
+        }
+    }
+    chan.drain_vec_write(&mut deaths);
+}
+
+fn death_handler(
+    world: &mut hecs::World,
+    hero: hecs::Entity,
+    chan: &EventChan,
+    r: &mut shrev::ReaderId<Event>,
+    log: &mut MessageLog,
+) {
     for ev in chan.read(r) {
-        if let Event::TakeDamage(e, _dmg) = ev {
+        if let Event::Died(e) = ev {
+            // The hero is kept around so the rest of the game can still look it up
+            if *e == hero {
+                log.add(
+                    gfx::RED_BRIGHT,
+                    "You are dead, the game is over. Press Escape to leave.",
+                );
+                continue;
+            }
             world.despawn(*e).expect("failed to despawn entity");
         }
     }
@@ -408,6 +1737,16 @@ enum Event {
     Move(hecs::Entity, Point),
     Collision(hecs::Entity, hecs::Entity),
     TakeDamage(hecs::Entity, i32),
+    Died(hecs::Entity),
+    PickUp(hecs::Entity),
+    PickedUp(hecs::Entity, hecs::Entity),
+    Drop(hecs::Entity, hecs::Entity),
+    Dropped(hecs::Entity, hecs::Entity),
+    Use(hecs::Entity, hecs::Entity),
+    Throw(hecs::Entity, hecs::Entity, Point),
+    Zap(hecs::Entity, hecs::Entity, Point),
+    Descend(hecs::Entity),
+    Ascend(hecs::Entity),
 }
 
 // Components
@@ -421,17 +1760,101 @@ struct Viewshed {
 #[derive(Debug, Clone)]
 struct Name(String);
 struct Player;
-struct AI;
+/// Marks AI that never fights and keeps its distance from the hero
+struct Coward;
+#[derive(Default)]
+struct AI {
+    state: AIState,
+}
+#[derive(Clone, Copy, Default, Hash, Serialize, Deserialize)]
+enum AIState {
+    #[default]
+    Idle,
+    Wandering,
+    Hunting {
+        last_seen: Point,
+    },
+    Fleeing {
+        from: Point,
+    },
+}
+#[derive(Clone, Serialize, Deserialize)]
+struct CombatStats {
+    max_hp: i32,
+    hp: i32,
+    power: i32,
+    defense: i32,
+}
+
+impl CombatStats {
+    fn new(max_hp: i32, power: i32, defense: i32) -> Self {
+        CombatStats {
+            max_hp,
+            hp: max_hp,
+            power,
+            defense,
+        }
+    }
+}
+struct Item;
+struct InBackpack {
+    owner: hecs::Entity,
+}
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum EquipmentSlot {
+    Melee,
+    Shield,
+}
+#[derive(Clone, Serialize, Deserialize)]
+struct Equippable {
+    slot: EquipmentSlot,
+    power: i32,
+    defense: i32,
+}
+struct Equipped {
+    owner: hecs::Entity,
+    slot: EquipmentSlot,
+}
+struct ProvidesHealing {
+    amount: i32,
+}
+struct InflictsDamage {
+    amount: i32,
+}
+/// Items that are used on a target tile within range
+struct Ranged {
+    range: i32,
+}
+#[derive(Clone, Copy, Serialize, Deserialize)]
 struct Explosive {
     radius: u8,
+    damage: i32,
+}
+
+impl Explosive {
+    /// Damage falls off linearly towards the edge of the blast
+    fn damage_at(&self, distance: f32) -> i32 {
+        let falloff = 1. - distance / (self.radius as f32 + 1.);
+        ((self.damage as f32 * falloff).round() as i32).max(1)
+    }
 }
 struct Explosion {
     duration_left: u8,
 }
 
+/// Letters selecting the items in the inventory, u, d and t are left out as
+/// they are the commands for the selected item
+const ITEM_HOTKEYS: &str = "abcefghijklmnopqrsvwxyz";
+const BACKPACK_CAPACITY: usize = ITEM_HOTKEYS.len();
+const THROWN_DAMAGE: i32 = 2;
+const THROW_RANGE: i32 = 6;
+
 // Map
 pub struct Map {
     pub entrance: Point,
+    pub exit: Point,
+    /// Rooms and corridors as the generator laid them out
+    pub layout: mapgen::Layout,
     pub tiles: Grid<Tile>,
     pub entities: Grid<Vec<hecs::Entity>>,
     pub blocked: Grid<bool>,
@@ -446,6 +1869,8 @@ impl Map {
         let explored = Grid::new(w, h, false);
         Map {
             entrance: pt(0, 0),
+            exit: pt(0, 0),
+            layout: mapgen::Layout::default(),
             tiles,
             entities,
             blocked,
@@ -468,11 +1893,14 @@ impl Map {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Tile {
     Wall,
     Floor,
     StairUp,
+    StairDown,
+    /// Can be walked through but not seen through
+    Door,
 }
 
 impl Tile {
@@ -481,6 +1909,239 @@ impl Tile {
     }
 
     fn opaque(&self) -> bool {
-        matches!(self, Tile::Wall)
+        matches!(self, Tile::Wall | Tile::Door)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_game(seed: u64) -> GameState {
+        let world = hecs::World::new();
+        let hero = world.reserve_entity();
+        let (map, _) = generate_map(60, 35, seed, 0).unwrap();
+        GameState::new(world, hero, map, seed, Raws::builtin())
+    }
+
+    /// Plays turns with actions chosen at random from seed
+    fn play(state: &mut GameState, turns: usize, seed: u64, mut check: impl FnMut(&GameState)) {
+        let mut sim = Simulation::new(state);
+        let moves = [pt(0, -1), pt(0, 1), pt(-1, 0), pt(1, 0)];
+        let mut rng: SipRng = Seeder::from(("test actions", seed)).make_rng();
+        for _ in 0..turns {
+            let action = match rng.gen_range(0..10) {
+                0 => Action::Wait,
+                1 => Action::PickUp,
+                2 => Action::Descend,
+                _ => Action::Move(*moves.choose(&mut rng).unwrap()),
+            };
+            sim.step(state, Some(action));
+            sim.animate(state);
+            check(state);
+        }
+    }
+
+    #[test]
+    fn simulate_turns_without_window() {
+        let mut state = new_game(1);
+        play(&mut state, 2000, 1, |state| {
+            let p = state.hero_position().unwrap();
+            assert!(
+                !state.map.tiles[(p.x, p.y)].blocked(),
+                "hero in a wall at {:?}",
+                p
+            );
+        });
+    }
+
+    #[test]
+    fn dead_hero_can_not_act() {
+        let mut state = new_game(1);
+        let mut sim = Simulation::new(&mut state);
+        let hero = state.hero;
+        let potion = state.world.spawn((
+            Item,
+            InBackpack { owner: hero },
+            ProvidesHealing { amount: 10 },
+        ));
+        state.world.get::<&mut CombatStats>(hero).unwrap().hp = 0;
+
+        let start = state.hero_position();
+        for d in [pt(0, -1), pt(0, 1), pt(-1, 0), pt(1, 0)] {
+            sim.step(&mut state, Some(Action::Move(d)));
+        }
+        sim.step(&mut state, Some(Action::Use(potion)));
+        assert_eq!(state.hero_position(), start);
+        assert_eq!(state.hero_hp().map(|(hp, _)| hp), Some(0));
+        assert!(state.world.contains(potion));
+        assert_eq!(state.turn(), 0);
+    }
+
+    #[test]
+    fn only_some_items_can_be_used() {
+        let mut state = new_game(1);
+        for (name, expected) in [
+            ("Healing Potion", true),
+            ("Dagger", true),
+            ("Exploding Flask", false),
+        ] {
+            let item = state.spawn(name, pt(0, 0)).unwrap();
+            assert_eq!(usable(&state.world, item), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn fleeing_monsters_do_not_attack() {
+        let mut state = new_game(1);
+        let mut sim = Simulation::new(&mut state);
+        let hero = state.hero_position().unwrap();
+        let ant = state.spawn("Giant Ant", hero).unwrap();
+        let hp = state.hero_hp();
+
+        state.world.get::<&mut AI>(ant).unwrap().state = AIState::Fleeing { from: hero };
+        state.chan.single_write(Event::Collision(ant, state.hero));
+        sim.step(&mut state, None);
+        assert_eq!(state.hero_hp(), hp);
+
+        state.world.get::<&mut AI>(ant).unwrap().state = AIState::Hunting { last_seen: hero };
+        state.chan.single_write(Event::Collision(ant, state.hero));
+        sim.step(&mut state, None);
+        assert_ne!(state.hero_hp(), hp);
+    }
+
+    #[test]
+    fn walking_into_a_trap_sets_it_off() {
+        let mut state = new_game(1);
+        let hero = state.hero_position().unwrap();
+        let step = [pt(0, -1), pt(0, 1), pt(-1, 0), pt(1, 0)]
+            .into_iter()
+            .find(|d| {
+                let p = hero + d.to_vector();
+                let taken = state
+                    .world
+                    .query::<&Position>()
+                    .iter()
+                    .any(|(_, pos)| pos.0 == p);
+                state.map.tiles[p] == Tile::Floor && !taken
+            })
+            .unwrap();
+        let trap = state.spawn("Fire Trap", hero + step.to_vector()).unwrap();
+        let mut sim = Simulation::new(&mut state);
+        sim.step(&mut state, Some(Action::Move(step)));
+        assert!(!state.world.contains(trap));
+        let (hp, max_hp) = state.hero_hp().unwrap();
+        assert!(hp < max_hp);
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        let run = |seed| {
+            let mut state = new_game(seed);
+            play(&mut state, 500, seed, |_| {});
+            let monsters: Vec<Point> = state
+                .world
+                .query::<&Position>()
+                .with::<&AI>()
+                .iter()
+                .map(|(_, p)| p.0)
+                .collect();
+            (
+                state.depth(),
+                state.hero_position(),
+                state.hero_hp(),
+                monsters,
+            )
+        };
+        assert_eq!(run(7), run(7));
+    }
+
+    #[test]
+    fn spawns_stay_off_walls_and_stairs() {
+        let raws = Raws::builtin();
+        let mut shallow = 0;
+        let mut deep = 0;
+        for seed in 0..20 {
+            for depth in [0, 5] {
+                let mut world = hecs::World::new();
+                let (map, _) = generate_map(60, 35, seed, depth).unwrap();
+                spawn_level_entities(&mut world, &raws, &map, seed, depth);
+                let mut seen = HashSet::new();
+                for (_, pos) in world.query::<&Position>().iter() {
+                    let p = pos.0;
+                    assert_eq!(map.tiles[p], Tile::Floor, "seed {} depth {}", seed, depth);
+                    assert!(p != map.entrance && p != map.exit);
+                    assert!(map.layout.room_at(p).is_some());
+                    assert!(seen.insert(p), "two spawns at {:?}", p);
+                }
+                if depth == 0 {
+                    shallow += seen.len();
+                } else {
+                    deep += seen.len();
+                }
+            }
+        }
+        assert!(deep > shallow, "{} spawns deep, {} shallow", deep, shallow);
+    }
+
+    #[test]
+    fn unknown_vault_spawns_are_skipped() {
+        let raws = Raws::builtin();
+        let (mut map, _) = generate_map(60, 35, 1, 0).unwrap();
+        map.layout.spawns.clear();
+        let mut without = hecs::World::new();
+        spawn_level_entities(&mut without, &raws, &map, 1, 0);
+
+        let p = map.entrance + pt(1, 0).to_vector();
+        map.layout.spawns = vec![(p, "Dragon".to_string())];
+        let mut world = hecs::World::new();
+        spawn_level_entities(&mut world, &raws, &map, 1, 0);
+        assert_eq!(world.len(), without.len());
+    }
+
+    #[test]
+    fn vault_spawns_are_defined() {
+        let raws = Raws::builtin();
+        for vault in Vault::builtin() {
+            for name in vault.legend.values() {
+                assert!(raws.get(name).is_ok(), "{}: {}", vault.name, name);
+            }
+        }
+    }
+
+    #[test]
+    fn replay_reproduces_game() {
+        let mut state = new_game(3);
+        let mut recording = Replay::new(&state);
+        let mut sim = Simulation::new(&mut state);
+        let moves = [pt(0, -1), pt(0, 1), pt(-1, 0), pt(1, 0)];
+        let mut rng: SipRng = Seeder::from("replay actions").make_rng();
+        for _ in 0..300 {
+            let action = match backpack(&state.world, state.hero).first() {
+                Some(item) if rng.gen_ratio(1, 10) => Action::Drop(*item),
+                _ if rng.gen_ratio(1, 5) => Action::PickUp,
+                _ => Action::Move(*moves.choose(&mut rng).unwrap()),
+            };
+            recording.record(&state, action);
+            sim.step(&mut state, Some(action));
+        }
+        recording.finish(&state);
+
+        let path = std::env::temp_dir().join(format!("replay-test-{}", std::process::id()));
+        recording.save(&path).unwrap();
+        let replay = Replay::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut replayed = replay.new_game(Raws::builtin()).unwrap();
+        let mut sim = Simulation::new(&mut replayed);
+        for n in 0..replay.len() {
+            let action = replay.action(n, &replayed).expect("replay out of sync");
+            sim.step(&mut replayed, Some(action));
+            // Explosions linger for different times when animated but must not
+            // change the outcome
+            sim.animate(&mut replayed);
+        }
+        assert!(replay.verify(&replayed));
+        assert_eq!(state.state_hash(), replayed.state_hash());
     }
 }
@@ -0,0 +1,179 @@
+use super::*;
+use std::collections::VecDeque;
+
+/// Oldest messages are forgotten once the log grows past this
+const LOG_CAPACITY: usize = 500;
+/// Rows below the map showing the latest messages
+pub const LOG_PANEL_HEIGHT: i32 = 6;
+
+#[derive(Clone)]
+pub struct Message {
+    pub turn: u64,
+    pub text: String,
+    pub color: graphics::Color,
+}
+
+/// Everything that happened in the game which the player should be told about
+#[derive(Default)]
+pub struct MessageLog {
+    turn: u64,
+    messages: VecDeque<Message>,
+}
+
+impl MessageLog {
+    /// Messages added from now on are stamped with turn
+    pub fn set_turn(&mut self, turn: u64) {
+        self.turn = turn;
+    }
+
+    pub fn add(&mut self, color: graphics::Color, text: impl Into<String>) {
+        if self.messages.len() == LOG_CAPACITY {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(Message {
+            turn: self.turn,
+            text: text.into(),
+            color,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Messages from oldest to newest
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Message> + ExactSizeIterator {
+        self.messages.iter()
+    }
+}
+
+/// Draws the latest messages that fit in the panel below a map of size
+pub fn draw_panel(
+    instances: &mut graphics::InstanceArray,
+    sprite_set: &gfx::SpriteSet,
+    log: &MessageLog,
+    width: i32,
+    height: i32,
+) {
+    let rows = LOG_PANEL_HEIGHT as usize - 1;
+    let mut lines: Vec<Vec<text::Cell>> = vec![];
+    for m in log.iter().rev() {
+        if lines.len() >= rows {
+            break;
+        }
+        let cells = text::parse(&m.text, m.color, None);
+        for line in text::wrap(&cells, width as usize).into_iter().rev() {
+            lines.push(line);
+        }
+    }
+    for (i, line) in lines.iter().take(rows).rev().enumerate() {
+        text::draw_cells(instances, sprite_set, pt(0, height + 1 + i as i32), line);
+    }
+}
+
+/// Full screen scrollback of every message in the log
+pub struct MessageHistory {
+    sprite_set: gfx::SpriteSet,
+    instances: Option<graphics::InstanceArray>,
+    width: i32,
+    height: i32,
+    /// How many messages the view is scrolled up from the newest
+    scroll: usize,
+}
+
+impl MessageHistory {
+    pub fn new(sprite_set: gfx::SpriteSet, width: i32, height: i32) -> Self {
+        MessageHistory {
+            sprite_set,
+            instances: None,
+            width,
+            height,
+            scroll: 0,
+        }
+    }
+
+    fn rows(&self) -> usize {
+        (self.height + LOG_PANEL_HEIGHT - 2) as usize
+    }
+}
+
+impl Scene<GameState> for MessageHistory {
+    fn update(&mut self, _ctx: &mut Context, state: &mut GameState) -> Transition<GameState> {
+        self.scroll = self.scroll.min(state.log.len().saturating_sub(self.rows()));
+        Transition::None
+    }
+
+    fn draw(&mut self, ctx: &mut Context, state: &mut GameState) -> GameResult {
+        let mut canvas = graphics::Canvas::from_frame(ctx, gfx::BACKGROUND);
+        canvas.set_sampler(graphics::Sampler::nearest_clamp());
+
+        let rows = self.rows();
+        let instances = self
+            .instances
+            .get_or_insert_with(|| graphics::InstanceArray::new(ctx, self.sprite_set.img.clone()));
+        instances.clear();
+        text::draw(
+            instances,
+            &self.sprite_set,
+            Box2D::new(pt(0, 0), pt(self.width, 1)),
+            "Messages ({yellow}up{/}/{yellow}down{/} to scroll, {yellow}esc{/} to close)",
+            gfx::WHITE_BRIGHT,
+            None,
+        );
+        let end = state.log.len().saturating_sub(self.scroll);
+        let start = end.saturating_sub(rows);
+        for (i, m) in state.log.iter().skip(start).take(end - start).enumerate() {
+            let turn = text::parse(&format!("{:>5} ", m.turn), gfx::BLACK_BRIGHT, None);
+            let mut line = text::parse(&m.text, m.color, None);
+            line.truncate(self.width as usize - turn.len());
+            text::draw_cells(instances, &self.sprite_set, pt(0, 2 + i as i32), &turn);
+            text::draw_cells(
+                instances,
+                &self.sprite_set,
+                pt(turn.len() as i32, 2 + i as i32),
+                &line,
+            );
+        }
+
+        let (offset, scale) = map_offset_and_scale(canvas.scissor_rect(), self.width, self.height);
+        canvas.draw(
+            &*instances,
+            graphics::DrawParam::new().dest(offset).scale(scale),
+        );
+        canvas.finish(ctx)
+    }
+
+    fn key_down(&mut self, input: KeyInput, _repeat: bool) -> Transition<GameState> {
+        let page = self.rows();
+        match input.keycode {
+            Some(KeyCode::Escape | KeyCode::M) => return Transition::Pop,
+            Some(KeyCode::Up) => self.scroll += 1,
+            Some(KeyCode::Down) => self.scroll = self.scroll.saturating_sub(1),
+            Some(KeyCode::PageUp) => self.scroll += page,
+            Some(KeyCode::PageDown) => self.scroll = self.scroll.saturating_sub(page),
+            _ => (),
+        }
+        Transition::None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn log_forgets_oldest_messages() {
+        let mut log = MessageLog::default();
+        for turn in 0..LOG_CAPACITY as u64 + 10 {
+            log.set_turn(turn);
+            log.add(gfx::WHITE, format!("message {}", turn));
+        }
+        assert_eq!(log.len(), LOG_CAPACITY);
+        let first = log.iter().next().unwrap();
+        assert_eq!((first.turn, first.text.as_str()), (10, "message 10"));
+    }
+}
@@ -0,0 +1,219 @@
+use super::*;
+use std::fs;
+
+const MAGIC: &[u8; 8] = b"RLRSRPLY";
+/// Bump whenever the layout of Replay changes, older replays are then rejected
+const VERSION: u32 = 2;
+
+/// Every action the hero took in a game started from a seed, enough to play
+/// the same game again
+#[derive(Serialize, Deserialize)]
+pub struct Replay {
+    seed: u64,
+    width: i32,
+    height: i32,
+    actions: Vec<RecordedAction>,
+    /// Hash of the game state after the last action
+    final_hash: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordedAction {
+    turn: u64,
+    action: ReplayAction,
+}
+
+/// Action with items referred to by their place in the hero's backpack, since
+/// entity ids are not meaningful outside of the world they were created in
+#[derive(Clone, Copy, Serialize, Deserialize)]
+enum ReplayAction {
+    Move(Point),
+    Wait,
+    PickUp,
+    Drop(usize),
+    Use(usize),
+    Throw(usize, Point),
+    Zap(usize, Point),
+    Descend,
+    Ascend,
+}
+
+impl Replay {
+    /// Starts recording a new game, state must not have taken any turns yet
+    pub fn new(state: &GameState) -> Self {
+        Replay {
+            seed: state.seed,
+            width: state.map.tiles.width,
+            height: state.map.tiles.height,
+            actions: vec![],
+            final_hash: state_hash(state),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// Records action which is about to be performed in state
+    pub fn record(&mut self, state: &GameState, action: Action) {
+        let items = backpack(&state.world, state.hero);
+        let slot = |item: hecs::Entity| {
+            items
+                .iter()
+                .position(|e| *e == item)
+                .expect("hero does not carry item")
+        };
+        let action = match action {
+            Action::Move(d) => ReplayAction::Move(d),
+            Action::Wait => ReplayAction::Wait,
+            Action::PickUp => ReplayAction::PickUp,
+            Action::Drop(item) => ReplayAction::Drop(slot(item)),
+            Action::Use(item) => ReplayAction::Use(slot(item)),
+            Action::Throw(item, p) => ReplayAction::Throw(slot(item), p),
+            Action::Zap(item, p) => ReplayAction::Zap(slot(item), p),
+            Action::Descend => ReplayAction::Descend,
+            Action::Ascend => ReplayAction::Ascend,
+        };
+        self.actions.push(RecordedAction {
+            turn: state.turn,
+            action,
+        });
+    }
+
+    /// Remembers what the game looks like after the recorded actions
+    pub fn finish(&mut self, state: &GameState) {
+        self.final_hash = state_hash(state);
+    }
+
+    /// The game as it was before the first recorded action
+    pub fn new_game(&self, raws: Raws) -> Result<GameState, GenerationError> {
+        let world = hecs::World::new();
+        let hero = world.reserve_entity();
+        let (map, _) = generate_map(self.width, self.height, self.seed, 0)?;
+        Ok(GameState::new(world, hero, map, self.seed, raws))
+    }
+
+    /// The nth recorded action as it applies to state, None when the replay
+    /// has run out or no longer matches the game
+    pub fn action(&self, n: usize, state: &GameState) -> Option<Action> {
+        let recorded = self.actions.get(n)?;
+        if recorded.turn != state.turn {
+            return None;
+        }
+        let items = backpack(&state.world, state.hero);
+        let item = |slot: usize| items.get(slot).copied();
+        Some(match recorded.action {
+            ReplayAction::Move(d) => Action::Move(d),
+            ReplayAction::Wait => Action::Wait,
+            ReplayAction::PickUp => Action::PickUp,
+            ReplayAction::Drop(slot) => Action::Drop(item(slot)?),
+            ReplayAction::Use(slot) => Action::Use(item(slot)?),
+            ReplayAction::Throw(slot, p) => Action::Throw(item(slot)?, p),
+            ReplayAction::Zap(slot, p) => Action::Zap(item(slot)?, p),
+            ReplayAction::Descend => Action::Descend,
+            ReplayAction::Ascend => Action::Ascend,
+        })
+    }
+
+    /// Whether state ended up where the recorded game did
+    pub fn verify(&self, state: &GameState) -> bool {
+        state_hash(state) == self.final_hash
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), SaveError> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        bincode::serialize_into(&mut data, self)
+            .map_err(|e| SaveError::Corrupt(format!("unable to encode replay: {}", e)))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SaveError> {
+        let data = fs::read(path)?;
+        if data.len() < MAGIC.len() + 4 || &data[..MAGIC.len()] != MAGIC {
+            return Err(SaveError::Corrupt("not a replay file".to_string()));
+        }
+        let mut version = [0; 4];
+        version.copy_from_slice(&data[MAGIC.len()..MAGIC.len() + 4]);
+        let version = u32::from_le_bytes(version);
+        if version != VERSION {
+            return Err(SaveError::Incompatible {
+                found: version,
+                expected: VERSION,
+            });
+        }
+        bincode::deserialize(&data[MAGIC.len() + 4..])
+            .map_err(|e| SaveError::Corrupt(e.to_string()))
+    }
+}
+
+/// Summary of everything that can differ between two runs of the game
+pub fn state_hash(state: &GameState) -> u64 {
+    let mut h = Fnv1a::new();
+    h.add(&(state.seed, state.turn, state.depth));
+    for t in state.map.tiles.iter() {
+        h.add(t);
+    }
+    for e in state.world.iter() {
+        // Explosions are only for show and linger for as long as they are drawn
+        if e.has::<Explosion>() {
+            continue;
+        }
+        if let Some(p) = e.get::<&Position>() {
+            h.add(&p.0);
+        }
+        if let Some(n) = e.get::<&Name>() {
+            h.add(&n.0);
+        }
+        if let Some(s) = e.get::<&CombatStats>() {
+            h.add(&(s.hp, s.max_hp, s.power, s.defense));
+        }
+        if let Some(ai) = e.get::<&AI>() {
+            h.add(&ai.state);
+        }
+        h.add(&(e.has::<InBackpack>(), e.has::<Equipped>()));
+    }
+    h.0
+}
+
+/// 64 bit FNV-1a over serialized values, unlike DefaultHasher it stays the
+/// same between Rust releases so replays can be checked on any toolchain
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn add<T: Serialize + ?Sized>(&mut self, value: &T) {
+        let bytes = bincode::serialize(value).expect("serializing state to hash");
+        for b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hash_is_fnv1a() {
+        let mut h = Fnv1a::new();
+        h.add(&b'a');
+        assert_eq!(h.0, 0xaf63_dc4c_8601_ec8c);
+    }
+}
@@ -0,0 +1,394 @@
+use super::*;
+use std::collections::HashMap;
+use std::{fmt, fs, io};
+
+const MAGIC: &[u8; 8] = b"RLRSSAVE";
+/// Bump whenever the layout of SaveGame changes, older saves are then rejected
+const VERSION: u32 = 7;
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(io::Error),
+    Corrupt(String),
+    Incompatible { found: u32, expected: u32 },
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Io(e) => write!(f, "unable to access save file: {}", e),
+            SaveError::Corrupt(reason) => write!(f, "save file is corrupt: {}", reason),
+            SaveError::Incompatible { found, expected } => write!(
+                f,
+                "save file has version {} but this game only reads version {}",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<io::Error> for SaveError {
+    fn from(e: io::Error) -> Self {
+        SaveError::Io(e)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SaveGame {
+    seed: u64,
+    turn: u64,
+    depth: usize,
+    /// Index of the hero in entities
+    hero: usize,
+    map: MapSave,
+    entities: Vec<EntitySave>,
+    levels: Vec<Option<LevelSave>>,
+    /// Turn, text and color of each message in the log
+    log: Vec<(u64, String, [f32; 4])>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LevelSave {
+    map: MapSave,
+    entities: Vec<EntitySave>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MapSave {
+    width: i32,
+    height: i32,
+    entrance: Point,
+    exit: Point,
+    layout: mapgen::Layout,
+    tiles: Vec<Tile>,
+    explored: Vec<bool>,
+}
+
+/// All components of one entity. References to other entities are stored as
+/// indices into the list of entities saved with it.
+#[derive(Default, Serialize, Deserialize)]
+struct EntitySave {
+    position: Option<Point>,
+    name: Option<String>,
+    renderable: Option<(gfx::CP437, [f32; 4])>,
+    viewshed_range: Option<i32>,
+    blocks_tile: bool,
+    player: bool,
+    ai: Option<AIState>,
+    coward: bool,
+    combat_stats: Option<CombatStats>,
+    item: bool,
+    in_backpack: Option<usize>,
+    equippable: Option<Equippable>,
+    equipped: Option<(usize, EquipmentSlot)>,
+    provides_healing: Option<i32>,
+    inflicts_damage: Option<i32>,
+    ranged: Option<i32>,
+    explosive: Option<Explosive>,
+    explosion: Option<u8>,
+}
+
+pub fn write(state: &GameState, path: &Path) -> Result<(), SaveError> {
+    let (entities, index) = save_entities(&state.world);
+    let save = SaveGame {
+        seed: state.seed,
+        turn: state.turn,
+        depth: state.depth,
+        hero: index[&state.hero],
+        map: save_map(&state.map),
+        entities,
+        levels: state
+            .levels
+            .iter()
+            .map(|l| {
+                l.as_ref().map(|l| LevelSave {
+                    map: save_map(&l.map),
+                    entities: save_entities(&l.world).0,
+                })
+            })
+            .collect(),
+        log: state
+            .log
+            .iter()
+            .map(|m| (m.turn, m.text.clone(), m.color.into()))
+            .collect(),
+    };
+
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    bincode::serialize_into(&mut data, &save)
+        .map_err(|e| SaveError::Corrupt(format!("unable to encode game: {}", e)))?;
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Write to a temporary file first so a crash never leaves a half written save
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+pub fn read(path: &Path, raws: Raws) -> Result<GameState, SaveError> {
+    let data = fs::read(path)?;
+    if data.len() < MAGIC.len() + 4 || &data[..MAGIC.len()] != MAGIC {
+        return Err(SaveError::Corrupt("not a save file".to_string()));
+    }
+    let mut version = [0; 4];
+    version.copy_from_slice(&data[MAGIC.len()..MAGIC.len() + 4]);
+    let version = u32::from_le_bytes(version);
+    if version != VERSION {
+        return Err(SaveError::Incompatible {
+            found: version,
+            expected: VERSION,
+        });
+    }
+    let save: SaveGame = bincode::deserialize(&data[MAGIC.len() + 4..])
+        .map_err(|e| SaveError::Corrupt(e.to_string()))?;
+
+    let mut world = hecs::World::new();
+    let entities = load_entities(&mut world, save.entities)?;
+    let hero = *entities
+        .get(save.hero)
+        .ok_or_else(|| SaveError::Corrupt("hero is missing".to_string()))?;
+    let map = load_map(save.map)?;
+    let levels = save
+        .levels
+        .into_iter()
+        .map(|l| match l {
+            Some(l) => {
+                let mut world = hecs::World::new();
+                load_entities(&mut world, l.entities)?;
+                Ok(Some(Level {
+                    map: load_map(l.map)?,
+                    world,
+                }))
+            }
+            None => Ok(None),
+        })
+        .collect::<Result<Vec<Option<Level>>, SaveError>>()?;
+    let mut log = MessageLog::default();
+    for (turn, text, color) in save.log {
+        log.set_turn(turn);
+        log.add(color.into(), text);
+    }
+    log.set_turn(save.turn);
+
+    Ok(GameState {
+        world,
+        hero,
+        raws,
+        chan: shrev::EventChannel::new(),
+        map,
+        seed: save.seed,
+        turn: save.turn,
+        depth: save.depth,
+        levels,
+        input: KeyState::default(),
+        log,
+        pending_action: None,
+    })
+}
+
+fn save_map(map: &Map) -> MapSave {
+    MapSave {
+        width: map.tiles.width,
+        height: map.tiles.height,
+        entrance: map.entrance,
+        exit: map.exit,
+        layout: map.layout.clone(),
+        tiles: map.tiles.iter().copied().collect(),
+        explored: map.explored.iter().copied().collect(),
+    }
+}
+
+fn load_map(save: MapSave) -> Result<Map, SaveError> {
+    if save.width <= 0 || save.height <= 0 {
+        return Err(SaveError::Corrupt("map has no size".to_string()));
+    }
+    let size = save
+        .width
+        .checked_mul(save.height)
+        .ok_or_else(|| SaveError::Corrupt("map is too large".to_string()))? as usize;
+    if save.tiles.len() != size || save.explored.len() != size {
+        return Err(SaveError::Corrupt("map size does not match".to_string()));
+    }
+    let inside = |p: Point| p.x >= 0 && p.y >= 0 && p.x < save.width && p.y < save.height;
+    if !inside(save.entrance) || !inside(save.exit) {
+        return Err(SaveError::Corrupt("stairs are outside the map".to_string()));
+    }
+    let mut map = Map::new(save.width, save.height);
+    map.entrance = save.entrance;
+    map.exit = save.exit;
+    map.layout = save.layout;
+    for (t, s) in map.tiles.iter_mut().zip(save.tiles) {
+        *t = s;
+    }
+    for (e, s) in map.explored.iter_mut().zip(save.explored) {
+        *e = s;
+    }
+    Ok(map)
+}
+
+fn save_entities(world: &hecs::World) -> (Vec<EntitySave>, HashMap<hecs::Entity, usize>) {
+    let all: Vec<hecs::Entity> = world.iter().map(|e| e.entity()).collect();
+    let index: HashMap<hecs::Entity, usize> =
+        all.iter().enumerate().map(|(i, e)| (*e, i)).collect();
+
+    let entities = all
+        .iter()
+        .map(|&e| EntitySave {
+            position: world.get::<&Position>(e).ok().map(|p| p.0),
+            name: world.get::<&Name>(e).ok().map(|n| n.0.clone()),
+            renderable: world
+                .get::<&Renderable>(e)
+                .ok()
+                .map(|r| (r.spr, r.color.into())),
+            viewshed_range: world.get::<&Viewshed>(e).ok().map(|v| v.range),
+            blocks_tile: world.satisfies::<&BlocksTile>(e).unwrap_or(false),
+            player: world.satisfies::<&Player>(e).unwrap_or(false),
+            ai: world.get::<&AI>(e).ok().map(|ai| ai.state),
+            coward: world.satisfies::<&Coward>(e).unwrap_or(false),
+            combat_stats: world.get::<&CombatStats>(e).ok().map(|s| (*s).clone()),
+            item: world.satisfies::<&Item>(e).unwrap_or(false),
+            in_backpack: world
+                .get::<&InBackpack>(e)
+                .ok()
+                .and_then(|b| index.get(&b.owner).copied()),
+            equippable: world.get::<&Equippable>(e).ok().map(|eq| (*eq).clone()),
+            equipped: world
+                .get::<&Equipped>(e)
+                .ok()
+                .and_then(|eq| Some((*index.get(&eq.owner)?, eq.slot))),
+            provides_healing: world.get::<&ProvidesHealing>(e).ok().map(|h| h.amount),
+            inflicts_damage: world.get::<&InflictsDamage>(e).ok().map(|d| d.amount),
+            ranged: world.get::<&Ranged>(e).ok().map(|r| r.range),
+            explosive: world.get::<&Explosive>(e).ok().map(|x| *x),
+            explosion: world.get::<&Explosion>(e).ok().map(|x| x.duration_left),
+        })
+        .collect();
+    (entities, index)
+}
+
+fn load_entities(
+    world: &mut hecs::World,
+    saved: Vec<EntitySave>,
+) -> Result<Vec<hecs::Entity>, SaveError> {
+    let mut builder = hecs::EntityBuilder::new();
+    let mut entities = vec![];
+    for s in &saved {
+        if let Some(p) = s.position {
+            builder.add(Position(p));
+        }
+        if let Some(n) = &s.name {
+            builder.add(Name(n.clone()));
+        }
+        if let Some((spr, color)) = s.renderable {
+            builder.add(Renderable {
+                spr,
+                color: color.into(),
+            });
+        }
+        if let Some(range) = s.viewshed_range {
+            builder.add(Viewshed {
+                visible_tiles: HashSet::new(),
+                range,
+                dirty: true,
+            });
+        }
+        if s.blocks_tile {
+            builder.add(BlocksTile);
+        }
+        if s.player {
+            builder.add(Player);
+        }
+        if let Some(state) = s.ai {
+            builder.add(AI { state });
+        }
+        if s.coward {
+            builder.add(Coward);
+        }
+        if let Some(stats) = &s.combat_stats {
+            builder.add(stats.clone());
+        }
+        if s.item {
+            builder.add(Item);
+        }
+        if let Some(eq) = &s.equippable {
+            builder.add(eq.clone());
+        }
+        if let Some(amount) = s.provides_healing {
+            builder.add(ProvidesHealing { amount });
+        }
+        if let Some(amount) = s.inflicts_damage {
+            builder.add(InflictsDamage { amount });
+        }
+        if let Some(range) = s.ranged {
+            builder.add(Ranged { range });
+        }
+        if let Some(explosive) = s.explosive {
+            builder.add(explosive);
+        }
+        if let Some(duration_left) = s.explosion {
+            builder.add(Explosion { duration_left });
+        }
+        entities.push(world.spawn(builder.build()));
+    }
+
+    // References can only be resolved once every entity exists
+    let entity_at = |i: usize| {
+        entities
+            .get(i)
+            .copied()
+            .ok_or_else(|| SaveError::Corrupt(format!("no entity with index {}", i)))
+    };
+    for (e, s) in entities.iter().zip(&saved) {
+        if let Some(owner) = s.in_backpack {
+            let owner = entity_at(owner)?;
+            world
+                .insert_one(*e, InBackpack { owner })
+                .expect("entity missing");
+        }
+        if let Some((owner, slot)) = s.equipped {
+            let owner = entity_at(owner)?;
+            world
+                .insert_one(*e, Equipped { owner, slot })
+                .expect("entity missing");
+        }
+    }
+    Ok(entities)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn damaged_saves_are_corrupt() {
+        let world = hecs::World::new();
+        let hero = world.reserve_entity();
+        let (map, _) = generate_map(60, 35, 1, 0).unwrap();
+        let state = GameState::new(world, hero, map, 1, Raws::builtin());
+
+        let path = std::env::temp_dir().join(format!("save-test-{}", std::process::id()));
+        state.save(&path).unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() / 2]).unwrap();
+        let loaded = GameState::load(&path, Raws::builtin());
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(loaded, Err(SaveError::Corrupt(_))));
+
+        let mut huge = save_map(&state.map);
+        huge.width = i32::MAX;
+        huge.height = 2;
+        assert!(matches!(load_map(huge), Err(SaveError::Corrupt(_))));
+
+        let mut stairs = save_map(&state.map);
+        stairs.exit = pt(stairs.width, 0);
+        assert!(matches!(load_map(stairs), Err(SaveError::Corrupt(_))));
+
+        assert!(load_map(save_map(&state.map)).is_ok());
+    }
+}
@@ -25,6 +25,10 @@ impl<T: std::clone::Clone> Grid<T> {
         x + y * self.width
     }
 
+    pub fn in_bounds(&self, p: Point) -> bool {
+        p.x >= 0 && p.x < self.width && p.y >= 0 && p.y < self.height
+    }
+
     pub fn iter_mut(&mut self) -> std::slice::IterMut<T> {
         self.storage.iter_mut()
     }
@@ -62,6 +66,29 @@ impl<T: std::clone::Clone> std::ops::IndexMut<Point> for Grid<T> {
     }
 }
 
+/// Tiles on the straight line from a to b, both ends included
+pub fn line(a: Point, b: Point) -> Vec<Point> {
+    let d = b - a;
+    let (dx, dy) = (d.x.abs(), -d.y.abs());
+    let (sx, sy) = (d.x.signum(), d.y.signum());
+    let mut err = dx + dy;
+    let mut p = a;
+    let mut points = vec![p];
+    while p != b {
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            p.x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            p.y += sy;
+        }
+        points.push(p);
+    }
+    points
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -75,4 +102,18 @@ mod test {
         };
         assert!(m[point2(0, 1)]);
     }
+
+    #[test]
+    fn line_between_points() {
+        assert_eq!(line(pt(1, 1), pt(1, 1)), vec![pt(1, 1)]);
+        assert_eq!(
+            line(pt(0, 0), pt(3, 0)),
+            vec![pt(0, 0), pt(1, 0), pt(2, 0), pt(3, 0)]
+        );
+        assert_eq!(line(pt(2, 2), pt(0, 0)), vec![pt(2, 2), pt(1, 1), pt(0, 0)]);
+        assert_eq!(
+            line(pt(0, 0), pt(4, 2)),
+            vec![pt(0, 0), pt(1, 1), pt(2, 1), pt(3, 2), pt(4, 2)]
+        );
+    }
 }
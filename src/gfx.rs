@@ -1,6 +1,8 @@
 #![allow(dead_code)]
 
 use ggez::graphics;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
 
 pub const BLACK: graphics::Color = graphics::Color {
     r: 0.184,
@@ -105,20 +107,403 @@ pub const BACKGROUND: graphics::Color = graphics::Color {
     a: 1.,
 };
 
-#[derive(Copy, Clone)]
+/// Color constant by its name in lower case, such as "yellow_bright"
+pub fn color_named(name: &str) -> Option<graphics::Color> {
+    Some(match name {
+        "black" => BLACK,
+        "blue" => BLUE,
+        "cyan" => CYAN,
+        "green" => GREEN,
+        "magenta" => MAGENTA,
+        "red" => RED,
+        "white" => WHITE,
+        "yellow" => YELLOW,
+        "black_bright" => BLACK_BRIGHT,
+        "blue_bright" => BLUE_BRIGHT,
+        "cyan_bright" => CYAN_BRIGHT,
+        "green_bright" => GREEN_BRIGHT,
+        "magenta_bright" => MAGENTA_BRIGHT,
+        "red_bright" => RED_BRIGHT,
+        "white_bright" => WHITE_BRIGHT,
+        "yellow_bright" => YELLOW_BRIGHT,
+        _ => return None,
+    })
+}
+
+/// Glyphs of code page 437, in the order they appear in the tileset
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
 pub enum CP437 {
+    Null = 0,
+    SmileyFace = 1,
+    SmileyFaceInverse = 2,
+    Heart = 3,
+    Diamond = 4,
+    Club = 5,
+    Spade = 6,
+    Bullet = 7,
+    BulletInverse = 8,
+    Circle = 9,
+    CircleInverse = 10,
+    Male = 11,
+    Female = 12,
+    Note = 13,
+    DoubleNote = 14,
+    Sun = 15,
+    TriangleRight = 16,
+    TriangleLeft = 17,
+    ArrowUpDown = 18,
+    DoubleExclamation = 19,
+    Pilcrow = 20,
+    Section = 21,
+    Bar = 22,
+    ArrowUpDownBase = 23,
+    ArrowUp = 24,
+    ArrowDown = 25,
+    ArrowRight = 26,
+    ArrowLeft = 27,
+    RightAngle = 28,
+    ArrowLeftRight = 29,
+    TriangleUp = 30,
+    TriangleDown = 31,
+    Space = 32,
+    Exclamation = 33,
+    Quote = 34,
     Pillar = 35,
+    Dollar = 36,
+    Percent = 37,
+    Ampersand = 38,
+    Apostrophe = 39,
+    LeftParen = 40,
+    RightParen = 41,
+    Asterisk = 42,
+    Plus = 43,
+    Comma = 44,
+    Minus = 45,
     ChDot = 46,
+    Slash = 47,
+    Digit0 = 48,
+    Digit1 = 49,
+    Digit2 = 50,
+    Digit3 = 51,
+    Digit4 = 52,
+    Digit5 = 53,
+    Digit6 = 54,
+    Digit7 = 55,
+    Digit8 = 56,
+    Digit9 = 57,
+    Colon = 58,
+    Semicolon = 59,
     LessThan = 60,
+    Equals = 61,
     GreaterThan = 62,
+    Question = 63,
     ChAt = 64,
     ChA = 65,
+    ChB = 66,
+    ChC = 67,
+    ChD = 68,
+    ChE = 69,
+    ChF = 70,
+    ChG = 71,
+    ChH = 72,
+    ChI = 73,
+    ChJ = 74,
+    ChK = 75,
+    ChL = 76,
+    ChM = 77,
+    ChN = 78,
+    ChO = 79,
+    ChP = 80,
+    ChQ = 81,
+    ChR = 82,
+    ChS = 83,
+    ChT = 84,
+    ChU = 85,
+    ChV = 86,
+    ChW = 87,
+    ChX = 88,
+    ChY = 89,
+    ChZ = 90,
+    LeftBracket = 91,
+    Backslash = 92,
+    RightBracket = 93,
     Trap = 94,
+    Underscore = 95,
+    Backtick = 96,
     Cha = 97,
+    Chb = 98,
+    Chc = 99,
+    Chd = 100,
+    Che = 101,
+    Chf = 102,
+    Chg = 103,
+    Chh = 104,
+    Chi = 105,
+    Chj = 106,
+    Chk = 107,
+    Chl = 108,
+    Chm = 109,
+    Chn = 110,
+    Cho = 111,
+    Chp = 112,
+    Chq = 113,
+    Chr = 114,
+    Chs = 115,
+    Cht = 116,
+    Chu = 117,
+    Chv = 118,
+    Chw = 119,
+    Chx = 120,
+    Chy = 121,
+    Chz = 122,
+    LeftBrace = 123,
+    Pipe = 124,
+    RightBrace = 125,
+    Tilde = 126,
+    House = 127,
+    CCedilla = 128,
+    UUmlaut = 129,
+    EAcute = 130,
+    AHat = 131,
+    AUmlaut = 132,
+    AGrave = 133,
+    ARing = 134,
+    CCedillaLower = 135,
+    EHat = 136,
+    EUmlaut = 137,
+    EGrave = 138,
+    IUmlaut = 139,
+    IHat = 140,
+    IGrave = 141,
+    AUmlautUpper = 142,
+    ARingUpper = 143,
+    EAcuteUpper = 144,
+    Ae = 145,
+    AeUpper = 146,
+    OHat = 147,
+    OUmlaut = 148,
+    OGrave = 149,
+    UHat = 150,
+    UGrave = 151,
+    YUmlaut = 152,
+    OUmlautUpper = 153,
+    UUmlautUpper = 154,
+    Cent = 155,
+    Pound = 156,
+    Yen = 157,
+    Peseta = 158,
+    Florin = 159,
+    AAcute = 160,
+    IAcute = 161,
+    OAcute = 162,
+    UAcute = 163,
+    NTilde = 164,
+    NTildeUpper = 165,
+    FeminineOrdinal = 166,
+    MasculineOrdinal = 167,
+    InvertedQuestion = 168,
+    ReversedNot = 169,
+    Not = 170,
+    Half = 171,
+    Quarter = 172,
+    InvertedExclamation = 173,
+    LeftGuillemet = 174,
+    RightGuillemet = 175,
     Filled1 = 176,
     Filled2 = 177,
     Filled3 = 178,
+    BoxV = 179,
+    BoxVL = 180,
+    BoxVLDouble = 181,
+    BoxVDoubleL = 182,
+    BoxDownDoubleL = 183,
+    BoxDownLDouble = 184,
+    BoxVDoubleLDouble = 185,
+    BoxVDouble = 186,
+    BoxDownLDoubleAll = 187,
+    BoxUpLDoubleAll = 188,
+    BoxUpDoubleL = 189,
+    BoxUpLDouble = 190,
+    BoxDownL = 191,
+    BoxUpR = 192,
+    BoxUpH = 193,
+    BoxDownH = 194,
+    BoxVR = 195,
+    BoxH = 196,
+    BoxVH = 197,
+    BoxVRDouble = 198,
+    BoxVDoubleR = 199,
+    BoxUpRDoubleAll = 200,
+    BoxDownRDoubleAll = 201,
+    BoxUpHDoubleAll = 202,
+    BoxDownHDoubleAll = 203,
+    BoxVRDoubleAll = 204,
+    BoxHDouble = 205,
+    BoxVHDoubleAll = 206,
+    BoxUpHDouble = 207,
+    BoxUpDoubleH = 208,
+    BoxDownHDouble = 209,
+    BoxDownDoubleH = 210,
+    BoxUpDoubleR = 211,
+    BoxUpRDouble = 212,
+    BoxDownRDouble = 213,
+    BoxDownDoubleR = 214,
+    BoxVDoubleH = 215,
+    BoxVHDouble = 216,
+    BoxUpL = 217,
+    BoxDownR = 218,
     Filled4 = 219,
+    HalfLower = 220,
+    HalfLeft = 221,
+    HalfRight = 222,
+    HalfUpper = 223,
+    Alpha = 224,
+    SharpS = 225,
+    Gamma = 226,
+    Pi = 227,
+    SigmaUpper = 228,
+    Sigma = 229,
+    Mu = 230,
+    Tau = 231,
+    PhiUpper = 232,
+    Theta = 233,
+    Omega = 234,
+    Delta = 235,
+    Infinity = 236,
+    Phi = 237,
+    Epsilon = 238,
+    Intersection = 239,
+    Identical = 240,
+    PlusMinus = 241,
+    GreaterEqual = 242,
+    LessEqual = 243,
+    IntegralTop = 244,
+    IntegralBottom = 245,
+    Divide = 246,
+    AlmostEqual = 247,
+    Degree = 248,
+    BulletOperator = 249,
+    MiddleDot = 250,
+    SquareRoot = 251,
+    SuperscriptN = 252,
+    Squared = 253,
+    Square = 254,
+    NonBreakingSpace = 255,
+}
+
+impl CP437 {
+    pub fn from_index(idx: u8) -> Self {
+        CP437_GLYPHS[idx as usize]
+    }
+
+    pub fn index(self) -> u8 {
+        self as u8
+    }
+}
+
+impl From<CP437> for char {
+    fn from(t: CP437) -> char {
+        CP437_CHARS[t as usize]
+    }
+}
+
+impl TryFrom<char> for CP437 {
+    type Error = UnknownGlyph;
+
+    fn try_from(c: char) -> Result<Self, Self::Error> {
+        cp437_index(c).map(CP437::from_index).ok_or(UnknownGlyph(c))
+    }
+}
+
+/// A character with no look-alike in code page 437
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownGlyph(pub char);
+
+impl fmt::Display for UnknownGlyph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no code page 437 glyph looks like {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownGlyph {}
+
+// Glyphs are stored as the character they look like, which keeps data files
+// readable and does not depend on the order of the variants
+impl Serialize for CP437 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_char((*self).into())
+    }
+}
+
+impl<'de> Deserialize<'de> for CP437 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let c = char::deserialize(deserializer)?;
+        CP437::try_from(c).map_err(de::Error::custom)
+    }
+}
+
+/// Each glyph of code page 437, by index
+#[rustfmt::skip]
+const CP437_GLYPHS: [CP437; 256] = {
+    use CP437::*;
+    [
+        Null, SmileyFace, SmileyFaceInverse, Heart, Diamond, Club, Spade, Bullet, BulletInverse, Circle,
+        CircleInverse, Male, Female, Note, DoubleNote, Sun, TriangleRight, TriangleLeft, ArrowUpDown,
+        DoubleExclamation, Pilcrow, Section, Bar, ArrowUpDownBase, ArrowUp, ArrowDown, ArrowRight,
+        ArrowLeft, RightAngle, ArrowLeftRight, TriangleUp, TriangleDown, Space, Exclamation, Quote,
+        Pillar, Dollar, Percent, Ampersand, Apostrophe, LeftParen, RightParen, Asterisk, Plus, Comma,
+        Minus, ChDot, Slash, Digit0, Digit1, Digit2, Digit3, Digit4, Digit5, Digit6, Digit7, Digit8,
+        Digit9, Colon, Semicolon, LessThan, Equals, GreaterThan, Question, ChAt, ChA, ChB, ChC, ChD,
+        ChE, ChF, ChG, ChH, ChI, ChJ, ChK, ChL, ChM, ChN, ChO, ChP, ChQ, ChR, ChS, ChT, ChU, ChV, ChW,
+        ChX, ChY, ChZ, LeftBracket, Backslash, RightBracket, Trap, Underscore, Backtick, Cha, Chb, Chc,
+        Chd, Che, Chf, Chg, Chh, Chi, Chj, Chk, Chl, Chm, Chn, Cho, Chp, Chq, Chr, Chs, Cht, Chu, Chv,
+        Chw, Chx, Chy, Chz, LeftBrace, Pipe, RightBrace, Tilde, House, CCedilla, UUmlaut, EAcute, AHat,
+        AUmlaut, AGrave, ARing, CCedillaLower, EHat, EUmlaut, EGrave, IUmlaut, IHat, IGrave,
+        AUmlautUpper, ARingUpper, EAcuteUpper, Ae, AeUpper, OHat, OUmlaut, OGrave, UHat, UGrave,
+        YUmlaut, OUmlautUpper, UUmlautUpper, Cent, Pound, Yen, Peseta, Florin, AAcute, IAcute, OAcute,
+        UAcute, NTilde, NTildeUpper, FeminineOrdinal, MasculineOrdinal, InvertedQuestion, ReversedNot,
+        Not, Half, Quarter, InvertedExclamation, LeftGuillemet, RightGuillemet, Filled1, Filled2,
+        Filled3, BoxV, BoxVL, BoxVLDouble, BoxVDoubleL, BoxDownDoubleL, BoxDownLDouble,
+        BoxVDoubleLDouble, BoxVDouble, BoxDownLDoubleAll, BoxUpLDoubleAll, BoxUpDoubleL, BoxUpLDouble,
+        BoxDownL, BoxUpR, BoxUpH, BoxDownH, BoxVR, BoxH, BoxVH, BoxVRDouble, BoxVDoubleR,
+        BoxUpRDoubleAll, BoxDownRDoubleAll, BoxUpHDoubleAll, BoxDownHDoubleAll, BoxVRDoubleAll,
+        BoxHDouble, BoxVHDoubleAll, BoxUpHDouble, BoxUpDoubleH, BoxDownHDouble, BoxDownDoubleH,
+        BoxUpDoubleR, BoxUpRDouble, BoxDownRDouble, BoxDownDoubleR, BoxVDoubleH, BoxVHDouble, BoxUpL,
+        BoxDownR, Filled4, HalfLower, HalfLeft, HalfRight, HalfUpper, Alpha, SharpS, Gamma, Pi,
+        SigmaUpper, Sigma, Mu, Tau, PhiUpper, Theta, Omega, Delta, Infinity, Phi, Epsilon, Intersection,
+        Identical, PlusMinus, GreaterEqual, LessEqual, IntegralTop, IntegralBottom, Divide, AlmostEqual,
+        Degree, BulletOperator, MiddleDot, SquareRoot, SuperscriptN, Squared, Square, NonBreakingSpace,
+    ]
+};
+
+/// The character each glyph of code page 437 looks like, by index
+#[rustfmt::skip]
+const CP437_CHARS: [char; 256] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼',
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+    ' ', '!', '"', '#', '$', '%', '&', '\'', '(', ')', '*', '+', ',', '-', '.', '/',
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', ':', ';', '<', '=', '>', '?',
+    '@', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O',
+    'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', '[', '\\', ']', '^', '_',
+    '`', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o',
+    'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', '{', '|', '}', '~', '⌂',
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Index of the code page 437 glyph that looks like c
+pub fn cp437_index(c: char) -> Option<u8> {
+    if (' '..='~').contains(&c) {
+        return Some(c as u8);
+    }
+    CP437_CHARS.iter().position(|g| *g == c).map(|i| i as u8)
 }
 
 #[derive(Copy, Clone)]
@@ -128,6 +513,7 @@ pub struct Renderable {
 }
 
 // SpriteSet contains metadata about the tilesheet but not the actual image
+#[derive(Clone)]
 pub struct SpriteSet {
     pub img: graphics::Image,
     cols: i32,
@@ -158,7 +544,16 @@ impl SpriteSet {
     }
 
     pub fn src(&self, t: CP437) -> graphics::Rect {
-        let idx = t as i32;
+        self.src_idx(t as i32)
+    }
+
+    /// Glyph looking like c, characters missing from the code page are shown
+    /// as a question mark
+    pub fn glyph(&self, c: char) -> graphics::Rect {
+        self.src(CP437::try_from(c).unwrap_or(CP437::Question))
+    }
+
+    fn src_idx(&self, idx: i32) -> graphics::Rect {
         if idx >= self.rows * self.cols {
             panic!("accessing sprite by idx outside sheet bounds at {}", idx)
         } else {
@@ -171,3 +566,21 @@ impl SpriteSet {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn glyphs_convert_to_and_from_chars() {
+        for idx in 0..=255 {
+            let t = CP437::from_index(idx);
+            assert_eq!(t.index(), idx);
+            assert_eq!(CP437::try_from(char::from(t)), Ok(t));
+        }
+        assert_eq!(CP437::try_from('@'), Ok(CP437::ChAt));
+        assert_eq!(CP437::try_from('█'), Ok(CP437::Filled4));
+        assert_eq!(CP437::try_from('╬'), Ok(CP437::BoxVHDoubleAll));
+        assert_eq!(CP437::try_from('€'), Err(UnknownGlyph('€')));
+    }
+}
@@ -0,0 +1,9 @@
+pub mod fov;
+pub mod game;
+pub mod geom;
+pub mod gfx;
+pub mod mapgen;
+pub mod pathfinding;
+pub mod raws;
+pub mod scene;
+pub mod text;
@@ -3,18 +3,14 @@ use ggez::{
     glam::*,
     graphics,
     input::keyboard::{KeyCode, KeyInput},
-    Context, GameResult,
+    Context, GameError, GameResult,
 };
-use mapgen::Generator;
-use rand_seeder::{Seeder, SipRng};
-use scene::{Scene, SceneStack, Transition};
-
-mod fov;
-mod game;
-mod geom;
-mod gfx;
-mod mapgen;
-mod scene;
+use roguelike_rust::{
+    game, geom, gfx,
+    raws::{Raws, RawsError},
+    scene::{self, Scene, SceneStack, Transition},
+};
+use std::{io, path};
 
 const SCREEN_WIDTH_TILES: i32 = 60;
 const SCREEN_HEIGHT_TILES: i32 = 35;
@@ -24,34 +20,67 @@ struct App {
     scenes: SceneStack<game::GameState>,
 }
 
+/// How the game is started from the command line
+enum Start {
+    New { seed: u64 },
+    Load,
+    Replay(path::PathBuf),
+}
+
 impl App {
-    fn new(ctx: &mut Context) -> GameResult<App> {
+    fn new(ctx: &mut Context, start: Start, raws: Raws) -> GameResult<App> {
         let image =
             graphics::Image::from_path(ctx, "/nice-curses.png").expect("unable to load resource");
         let sprite_set = gfx::SpriteSet::new(image, 16, 16, 12, 12);
-        let world = hecs::World::new();
-        let hero = world.reserve_entity();
-        let mut state = game::GameState::new(
-            world,
-            hero,
-            sprite_set,
-            game::Map::new(SCREEN_WIDTH_TILES, SCREEN_HEIGHT_TILES),
-        );
+        let (mut state, timeline, replay) = match start {
+            Start::Load => {
+                let path = game::save_path(ctx);
+                let state = game::GameState::load(&path, raws).map_err(|e| {
+                    GameError::CustomError(format!("loading {}: {}", path.display(), e))
+                })?;
+                (state, None, None)
+            }
+            Start::Replay(path) => {
+                let replay = game::Replay::load(&path).map_err(|e| {
+                    GameError::CustomError(format!("loading {}: {}", path.display(), e))
+                })?;
+                let state = replay.new_game(raws).map_err(|e| {
+                    GameError::CustomError(format!("starting replay {}: {}", path.display(), e))
+                })?;
+                (state, None, Some(replay))
+            }
+            Start::New { seed } => {
+                let world = hecs::World::new();
+                let hero = world.reserve_entity();
+                let (map, timeline) =
+                    game::generate_map(SCREEN_WIDTH_TILES, SCREEN_HEIGHT_TILES, seed, 0).map_err(
+                        |e| GameError::CustomError(format!("generating the first level: {}", e)),
+                    )?;
+                let state = game::GameState::new(world, hero, map, seed, raws);
+                (state, Some(timeline), None)
+            }
+        };
+        println!("Seed: {}", state.seed());
+        ctx.gfx
+            .set_window_title(&format!("roguelike-rust (seed {})", state.seed()));
 
-        let mut rng: SipRng = Seeder::from("helloworld").make_rng();
-        let mut map_gen_visualizer =
-            mapgen::SimpleMapGenerator::new(state.map.tiles.width, state.map.tiles.height);
-        map_gen_visualizer.run(&mut rng, &mut state.map);
-        let mut scenes = SceneStack::new(Box::new(game::Game::new(
+        let mut game = game::Game::new(
             ctx,
             &mut state,
+            sprite_set,
             SCREEN_WIDTH_TILES,
             SCREEN_HEIGHT_TILES,
-        )));
-        scenes.push(Box::new(MapGenViewer {
-            history: map_gen_visualizer.timeline(),
-            cur: 0,
-        }));
+        );
+        match replay {
+            Some(replay) => game.play(replay),
+            // Games continued from a save can not be replayed from their seed
+            None if timeline.is_some() => game.record(game::Replay::new(&state)),
+            None => (),
+        }
+        let mut scenes = SceneStack::new(Box::new(game));
+        if let Some(history) = timeline {
+            scenes.push(Box::new(MapGenViewer { history, cur: 0 }));
+        }
 
         Ok(App { state, scenes })
     }
@@ -88,7 +117,7 @@ impl event::EventHandler<ggez::GameError> for App {
 }
 
 fn main() -> GameResult {
-    use std::{env, path};
+    use std::env;
     let resource_dir = if let Ok(manifest_dir) = env::var("CARGO_MANIFEST_DIR") {
         let mut path = path::PathBuf::from(manifest_dir);
         path.push("resources");
@@ -96,6 +125,7 @@ fn main() -> GameResult {
     } else {
         path::PathBuf::from("./resources")
     };
+    let raws = load_raws(&resource_dir.join("raws.ron"))?;
 
     let cb = ggez::ContextBuilder::new("roguelike-rust", "bjorngylling")
         .window_mode(
@@ -107,14 +137,47 @@ fn main() -> GameResult {
         .window_setup(conf::WindowSetup::default().title("roguelike-rust"))
         .add_resource_path(resource_dir);
 
+    let arg_after = |flag: &str| env::args().skip_while(|a| a != flag).nth(1);
+    let start = if env::args().any(|a| a == "--load") {
+        Start::Load
+    } else if let Some(replay) = arg_after("--replay") {
+        Start::Replay(path::PathBuf::from(replay))
+    } else {
+        let seed = match arg_after("--seed") {
+            Some(s) => s
+                .parse()
+                .map_err(|e| GameError::CustomError(format!("invalid seed {}: {}", s, e)))?,
+            None => rand::random(),
+        };
+        Start::New { seed }
+    };
+
     let (mut ctx, event_loop) = cb.build()?;
-    ctx.gfx.set_window_title("roguelike-rust");
     ctx.gfx.set_resizable(true)?;
 
-    let state = App::new(&mut ctx)?;
+    let state = App::new(&mut ctx, start, raws)?;
     event::run(ctx, event_loop, state)
 }
 
+/// The definitions in the file at path, or the built in ones if it is missing
+fn load_raws(path: &path::Path) -> GameResult<Raws> {
+    match Raws::load(path) {
+        Ok(raws) => Ok(raws),
+        Err(RawsError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!(
+                "{} not found, using the built in definitions",
+                path.display()
+            );
+            Ok(Raws::builtin())
+        }
+        Err(e) => Err(GameError::CustomError(format!(
+            "loading {}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
 struct MapGenViewer {
     history: Vec<geom::Grid<graphics::Color>>,
     cur: usize,
@@ -1,16 +1,147 @@
 use crate::game::{Map, Tile};
-use crate::geom::{pt, Grid, Point};
+use crate::geom::{line, pt, Grid, Point};
 use crate::gfx;
 use euclid::Box2D;
 use ggez::graphics;
 use rand::Rng;
-use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 
+mod pipeline;
+mod prefab;
+pub use pipeline::{
+    Build, CullUnreachable, Doors, Mirror, Pipeline, Stage, StairsFarApart, Symmetry,
+    WidenCorridors,
+};
+pub use prefab::{PlaceVaults, PrefabError, Vault};
+
+/// Lays out a level from scratch, the first stage of a Pipeline
 pub trait Generator {
-    fn run(&mut self, rng: &mut impl Rng, map: &mut Map);
+    /// Carves the level into map, returning how it is laid out
+    fn run(&mut self, rng: &mut impl Rng, map: &mut Map) -> Result<Layout, GenerationError>;
     fn timeline(&self) -> Vec<Grid<graphics::Color>>;
 }
 
+#[derive(Debug)]
+pub enum GenerationError {
+    /// The map is too small for the generators to work with
+    TooSmall { width: i32, height: i32 },
+    /// Not a single room fit on the map
+    NoRooms,
+    /// Too little was dug out to put the stairs in
+    NoFloor,
+    /// A stair is missing or both are on the same tile
+    MissingStairs,
+    /// Number of open tiles which could not be joined up with the entrance
+    Unreachable(usize),
+}
+
+impl fmt::Display for GenerationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenerationError::TooSmall { width, height } => {
+                write!(f, "a map of {}x{} is too small", width, height)
+            }
+            GenerationError::NoRooms => write!(f, "no rooms fit on the map"),
+            GenerationError::NoFloor => write!(f, "too little floor for the stairs"),
+            GenerationError::MissingStairs => write!(f, "the stairs are missing"),
+            GenerationError::Unreachable(n) => {
+                write!(f, "{} tiles can not be reached from the entrance", n)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GenerationError {}
+
+/// How a generated level is laid out, for whatever gets placed on it later
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Layout {
+    pub rooms: Vec<Box2D<i32, i32>>,
+    pub corridors: Vec<Corridor>,
+    pub entrance: Point,
+    pub exit: Point,
+    pub tags: Vec<(Region, Tag)>,
+    /// Things placed by hand, by the name they are defined as in the raws
+    pub spawns: Vec<(Point, String)>,
+}
+
+/// A corridor joining two rooms, given as indices into Layout::rooms
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Corridor {
+    pub from: usize,
+    pub to: usize,
+    /// Where the corridor starts, turns and ends, each leg is straight
+    pub points: Vec<Point>,
+}
+
+/// A part of a layout
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Region {
+    Room(usize),
+    Corridor(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Tag {
+    /// Holds the stairs up
+    Entrance,
+    /// Holds the stairs down
+    Exit,
+    /// Only one corridor leads to it
+    DeadEnd,
+    /// Part of a natural cave rather than a built room
+    Cave,
+    /// Stamped in from a prefab
+    Vault,
+}
+
+impl Layout {
+    /// Regions tagged with tag
+    pub fn tagged(&self, tag: Tag) -> impl Iterator<Item = Region> + '_ {
+        self.tags
+            .iter()
+            .filter(move |(_, t)| *t == tag)
+            .map(|(r, _)| *r)
+    }
+
+    pub fn has_tag(&self, region: Region, tag: Tag) -> bool {
+        self.tags.contains(&(region, tag))
+    }
+
+    /// Index of the room containing p
+    pub fn room_at(&self, p: Point) -> Option<usize> {
+        self.rooms.iter().position(|r| r.contains(p))
+    }
+
+    /// Tags the rooms holding the stairs and those at the end of a single
+    /// corridor
+    fn tag_rooms(&mut self) {
+        self.tag_stairs();
+        for i in 0..self.rooms.len() {
+            let corridors = self
+                .corridors
+                .iter()
+                .filter(|c| c.from == i || c.to == i)
+                .count();
+            if corridors == 1 {
+                self.tags.push((Region::Room(i), Tag::DeadEnd));
+            }
+        }
+    }
+
+    /// Tags the rooms holding the stairs, replacing earlier stair tags
+    fn tag_stairs(&mut self) {
+        self.tags
+            .retain(|(_, t)| !matches!(t, Tag::Entrance | Tag::Exit));
+        for (p, tag) in [(self.entrance, Tag::Entrance), (self.exit, Tag::Exit)] {
+            if let Some(i) = self.room_at(p) {
+                self.tags.push((Region::Room(i), tag));
+            }
+        }
+    }
+}
+
 pub struct SimpleMapGenerator {
     timeline: Vec<Grid<graphics::Color>>,
     colors: Vec<graphics::Color>,
@@ -94,11 +225,14 @@ impl SimpleMapGenerator {
 }
 
 impl Generator for SimpleMapGenerator {
-    fn run(&mut self, rng: &mut impl Rng, map: &mut Map) {
+    fn run(&mut self, rng: &mut impl Rng, map: &mut Map) -> Result<Layout, GenerationError> {
         let mut rooms: Vec<Box2D<i32, i32>> = vec![];
         for _ in 0..30 {
             let w = rng.gen_range(5..=12);
             let h = rng.gen_range((w - 3)..=(w + 3));
+            if w + 2 > self.bounds.width() || h + 2 > self.bounds.height() {
+                continue;
+            }
             let x = rng.gen_range(1..self.bounds.width() - w);
             let y = rng.gen_range(1..self.bounds.height() - h);
             let room = Box2D {
@@ -118,24 +252,18 @@ impl Generator for SimpleMapGenerator {
             }
         }
 
-        let mut connected: HashSet<Point> = HashSet::new();
-        for room in rooms.iter() {
-            if connected.len() == rooms.len() - 1 {
-                break;
-            }
+        if rooms.is_empty() {
+            return Err(GenerationError::NoRooms);
+        }
 
-            // find closest other room
-            let tar = rooms
+        // Join each room to the closest one placed before it, so every room
+        // can be reached from the first
+        let mut corridors = vec![];
+        for (from, room) in rooms.iter().enumerate().skip(1) {
+            let (to, tar) = rooms[..from]
                 .iter()
-                .filter(|&r| r.center() != room.center())
-                .filter(|&r| !connected.contains(&r.center()))
-                .min_by(|a, b| {
-                    room.center()
-                        .to_f32()
-                        .distance_to(a.center().to_f32())
-                        .partial_cmp(&room.center().to_f32().distance_to(b.center().to_f32()))
-                        .unwrap()
-                })
+                .enumerate()
+                .min_by_key(|(_, r)| (r.center() - room.center()).square_length())
                 .unwrap();
 
             // connect the rooms
@@ -145,7 +273,15 @@ impl Generator for SimpleMapGenerator {
             for y in room.center().y.min(tar.center().y)..=room.center().y.max(tar.center().y) {
                 self.m[(tar.center().x, y)] = 0;
             }
-            connected.insert(room.center());
+            corridors.push(Corridor {
+                from,
+                to,
+                points: vec![
+                    room.center(),
+                    pt(tar.center().x, room.center().y),
+                    tar.center(),
+                ],
+            });
             self.snapshot_corridor(vec![
                 pt(room.center().x.min(tar.center().x), room.center().y),
                 pt(room.center().x.max(tar.center().x), room.center().y),
@@ -162,11 +298,667 @@ impl Generator for SimpleMapGenerator {
             }
         }
         // Put the entrance in the center of the first room
-        map.tiles[rooms.first().unwrap().center()] = Tile::StairUp;
-        map.entrance = rooms.first().unwrap().center();
+        let entrance = rooms[0].center();
+        map.tiles[entrance] = Tile::StairUp;
+
+        // and the exit in the room furthest away from it
+        let exit = rooms
+            .iter()
+            .map(|r| r.center())
+            .max_by_key(|c| (*c - entrance).square_length())
+            .filter(|c| *c != entrance)
+            .unwrap_or(entrance + pt(1, 0).to_vector());
+        map.tiles[exit] = Tile::StairDown;
+
+        let mut layout = Layout {
+            rooms,
+            corridors,
+            entrance,
+            exit,
+            tags: vec![],
+            spawns: vec![],
+        };
+        layout.tag_rooms();
+        Ok(layout)
     }
 
     fn timeline(&self) -> Vec<Grid<graphics::Color>> {
         self.timeline.clone()
     }
 }
+
+/// Organic caves grown by a cellular automaton from random noise
+pub struct CaveGenerator {
+    timeline: Vec<Grid<graphics::Color>>,
+    /// 1 is rock, 0 is open
+    m: Grid<u8>,
+}
+
+/// Chance of a tile starting out as rock, in percent
+const CAVE_FILL: u32 = 45;
+const CAVE_SMOOTHING_STEPS: usize = 5;
+/// Caves are cut into chunks this big to give spawners rooms to fill
+const CAVE_CHUNK: i32 = 10;
+
+impl CaveGenerator {
+    pub fn new(width: i32, height: i32) -> Self {
+        CaveGenerator {
+            timeline: vec![Grid::new(width, height, gfx::BACKGROUND)],
+            m: Grid::new(width, height, 1),
+        }
+    }
+
+    fn on_edge(&self, x: i32, y: i32) -> bool {
+        x == 0 || y == 0 || x == self.m.width - 1 || y == self.m.height - 1
+    }
+
+    /// Rock stays where most of the surrounding tiles are rock
+    fn smooth(&mut self) {
+        let mut next = self.m.clone();
+        for y in 0..self.m.height {
+            for x in 0..self.m.width {
+                if self.on_edge(x, y) {
+                    continue;
+                }
+                let mut rock = 0;
+                for dy in -1..=1 {
+                    for dx in -1..=1 {
+                        rock += self.m[(x + dx, y + dy)];
+                    }
+                }
+                next[(x, y)] = u8::from(rock >= 5);
+            }
+        }
+        self.m = next;
+    }
+
+    /// Fills in every pocket but the largest
+    fn keep_largest_cave(&mut self) {
+        let mut seen = Grid::new(self.m.width, self.m.height, false);
+        let mut largest = vec![];
+        for y in 0..self.m.height {
+            for x in 0..self.m.width {
+                if self.m[(x, y)] != 0 || seen[(x, y)] {
+                    continue;
+                }
+                let cave = flood_fill(&self.m, pt(x, y));
+                for &p in &cave {
+                    seen[p] = true;
+                }
+                if cave.len() > largest.len() {
+                    largest = cave;
+                }
+            }
+        }
+
+        let mut keep = Grid::new(self.m.width, self.m.height, false);
+        for &p in &largest {
+            keep[p] = true;
+        }
+        let mut img = self.timeline.last().unwrap().clone();
+        for y in 0..self.m.height {
+            for x in 0..self.m.width {
+                if self.m[(x, y)] == 0 && !keep[(x, y)] {
+                    self.m[(x, y)] = 1;
+                    img[(x, y)] = gfx::RED;
+                }
+            }
+        }
+        self.timeline.push(img);
+    }
+}
+
+impl Generator for CaveGenerator {
+    fn run(&mut self, rng: &mut impl Rng, map: &mut Map) -> Result<Layout, GenerationError> {
+        for y in 0..self.m.height {
+            for x in 0..self.m.width {
+                let rock = self.on_edge(x, y) || rng.gen_ratio(CAVE_FILL, 100);
+                self.m[(x, y)] = u8::from(rock);
+            }
+        }
+        self.timeline.push(open_tiles(&self.m, gfx::BLACK_BRIGHT));
+        for _ in 0..CAVE_SMOOTHING_STEPS {
+            self.smooth();
+            self.timeline.push(open_tiles(&self.m, gfx::WHITE));
+        }
+        self.keep_largest_cave();
+        self.timeline.push(open_tiles(&self.m, gfx::WHITE_BRIGHT));
+        cave_layout(&self.m, rng, map)
+    }
+
+    fn timeline(&self) -> Vec<Grid<graphics::Color>> {
+        self.timeline.clone()
+    }
+}
+
+/// Image of the open tiles of m in color
+fn open_tiles(m: &Grid<u8>, color: graphics::Color) -> Grid<graphics::Color> {
+    let mut img = Grid::new(m.width, m.height, gfx::BACKGROUND);
+    for (c, m) in img.iter_mut().zip(m.iter()) {
+        if *m == 0 {
+            *c = color;
+        }
+    }
+    img
+}
+
+/// Open tiles of m reachable from start, in the order they are reached
+fn flood_fill(m: &Grid<u8>, start: Point) -> Vec<Point> {
+    let mut seen = Grid::new(m.width, m.height, false);
+    let mut reached = vec![start];
+    seen[start] = true;
+    let mut i = 0;
+    while i < reached.len() {
+        let p = reached[i];
+        for d in [pt(0, -1), pt(0, 1), pt(-1, 0), pt(1, 0)] {
+            let n = p + d.to_vector();
+            if m.in_bounds(n) && m[n] == 0 && !seen[n] {
+                seen[n] = true;
+                reached.push(n);
+            }
+        }
+        i += 1;
+    }
+    reached
+}
+
+/// Carves the open tiles of m, which must all be connected, into map and lays
+/// them out as cave chunks with stairs far apart
+fn cave_layout(m: &Grid<u8>, rng: &mut impl Rng, map: &mut Map) -> Result<Layout, GenerationError> {
+    let mut open = vec![];
+    for y in 0..m.height {
+        for x in 0..m.width {
+            if m[(x, y)] == 0 {
+                map.tiles[(x, y)] = Tile::Floor;
+                open.push(pt(x, y));
+            }
+        }
+    }
+    if open.len() < 2 {
+        return Err(GenerationError::NoFloor);
+    }
+    // Enter at a random spot and leave from the one furthest from it
+    let entrance = open[rng.gen_range(0..open.len())];
+    let exit = *flood_fill(m, entrance).last().unwrap();
+    if exit == entrance {
+        return Err(GenerationError::NoFloor);
+    }
+    map.tiles[entrance] = Tile::StairUp;
+    map.tiles[exit] = Tile::StairDown;
+
+    let bounds = Box2D::new(pt(0, 0), pt(m.width, m.height));
+    let mut rooms = vec![];
+    for y in (0..m.height).step_by(CAVE_CHUNK as usize) {
+        for x in (0..m.width).step_by(CAVE_CHUNK as usize) {
+            let chunk = Box2D::new(pt(x, y), pt(x + CAVE_CHUNK, y + CAVE_CHUNK))
+                .intersection_unchecked(&bounds);
+            if open.iter().any(|p| chunk.contains(*p)) {
+                rooms.push(chunk);
+            }
+        }
+    }
+    let mut layout = Layout {
+        rooms,
+        corridors: vec![],
+        entrance,
+        exit,
+        tags: vec![],
+        spawns: vec![],
+    };
+    layout.tag_rooms();
+    for i in 0..layout.rooms.len() {
+        layout.tags.push((Region::Room(i), Tag::Cave));
+    }
+    Ok(layout)
+}
+
+/// Rooms placed in the leaves of a binary space partition of the map, joined
+/// up the tree so every split is crossed by one corridor
+pub struct BspGenerator {
+    timeline: Vec<Grid<graphics::Color>>,
+    bounds: Box2D<i32, i32>,
+    /// 1 is rock, 0 is open
+    m: Grid<u8>,
+}
+
+/// Areas are not split into parts narrower than this
+const BSP_MIN_LEAF: i32 = 8;
+
+impl BspGenerator {
+    pub fn new(width: i32, height: i32) -> Self {
+        BspGenerator {
+            timeline: vec![Grid::new(width, height, gfx::BACKGROUND)],
+            bounds: Box2D::new(pt(0, 0), pt(width, height)),
+            m: Grid::new(width, height, 1),
+        }
+    }
+
+    fn snapshot(&mut self, tiles: impl Iterator<Item = Point>, color: graphics::Color) {
+        let mut img = self.timeline.last().unwrap().clone();
+        for p in tiles {
+            img[p] = color;
+        }
+        self.timeline.push(img);
+    }
+
+    /// Splits area until the parts are too small, then places a room in each.
+    /// Returns the index of a room in area for the corridor joining it to its
+    /// sibling.
+    fn partition(
+        &mut self,
+        rng: &mut impl Rng,
+        area: Box2D<i32, i32>,
+        rooms: &mut Vec<Box2D<i32, i32>>,
+        corridors: &mut Vec<Corridor>,
+    ) -> usize {
+        let (w, h) = (area.width(), area.height());
+        let can_split_x = w >= 2 * BSP_MIN_LEAF;
+        let can_split_y = h >= 2 * BSP_MIN_LEAF;
+        let split_x = match (can_split_x, can_split_y) {
+            (false, false) => return self.place_room(rng, area, rooms),
+            (true, false) => true,
+            (false, true) => false,
+            // Prefer cutting across the long side to avoid thin slivers
+            (true, true) if w * 4 > h * 5 => true,
+            (true, true) if h * 4 > w * 5 => false,
+            (true, true) => rng.gen(),
+        };
+
+        let (a, b) = if split_x {
+            let x = rng.gen_range(area.min.x + BSP_MIN_LEAF..=area.max.x - BSP_MIN_LEAF);
+            self.snapshot(
+                (area.min.y..area.max.y).map(|y| pt(x, y)),
+                gfx::BLACK_BRIGHT,
+            );
+            (
+                Box2D::new(area.min, pt(x, area.max.y)),
+                Box2D::new(pt(x, area.min.y), area.max),
+            )
+        } else {
+            let y = rng.gen_range(area.min.y + BSP_MIN_LEAF..=area.max.y - BSP_MIN_LEAF);
+            self.snapshot(
+                (area.min.x..area.max.x).map(|x| pt(x, y)),
+                gfx::BLACK_BRIGHT,
+            );
+            (
+                Box2D::new(area.min, pt(area.max.x, y)),
+                Box2D::new(pt(area.min.x, y), area.max),
+            )
+        };
+        let from = self.partition(rng, a, rooms, corridors);
+        let to = self.partition(rng, b, rooms, corridors);
+        self.connect(from, to, rooms, corridors);
+        from
+    }
+
+    /// Places a room inside leaf, leaving a wall on every side
+    fn place_room(
+        &mut self,
+        rng: &mut impl Rng,
+        leaf: Box2D<i32, i32>,
+        rooms: &mut Vec<Box2D<i32, i32>>,
+    ) -> usize {
+        let w = rng.gen_range(3..=leaf.width() - 2);
+        let h = rng.gen_range(3..=leaf.height() - 2);
+        let x = rng.gen_range(leaf.min.x + 1..=leaf.max.x - 1 - w);
+        let y = rng.gen_range(leaf.min.y + 1..=leaf.max.y - 1 - h);
+        let room = Box2D::new(pt(x, y), pt(x + w, y + h));
+        for y in room.y_range() {
+            for x in room.x_range() {
+                self.m[(x, y)] = 0;
+            }
+        }
+        let tiles: Vec<Point> = room
+            .y_range()
+            .flat_map(|y| room.x_range().map(move |x| pt(x, y)))
+            .collect();
+        self.snapshot(tiles.into_iter(), gfx::WHITE);
+        rooms.push(room);
+        rooms.len() - 1
+    }
+
+    /// Carves an L shaped corridor between the centers of two rooms
+    fn connect(
+        &mut self,
+        from: usize,
+        to: usize,
+        rooms: &[Box2D<i32, i32>],
+        corridors: &mut Vec<Corridor>,
+    ) {
+        let (a, b) = (rooms[from].center(), rooms[to].center());
+        let points = vec![a, pt(b.x, a.y), b];
+        let mut tiles = vec![];
+        for w in points.windows(2) {
+            tiles.extend(line(w[0], w[1]));
+        }
+        for &p in &tiles {
+            self.m[p] = 0;
+        }
+        self.snapshot(tiles.into_iter(), gfx::YELLOW);
+        corridors.push(Corridor { from, to, points });
+    }
+}
+
+impl Generator for BspGenerator {
+    fn run(&mut self, rng: &mut impl Rng, map: &mut Map) -> Result<Layout, GenerationError> {
+        if self.bounds.width() < BSP_MIN_LEAF || self.bounds.height() < BSP_MIN_LEAF {
+            return Err(GenerationError::TooSmall {
+                width: self.bounds.width(),
+                height: self.bounds.height(),
+            });
+        }
+        let mut rooms = vec![];
+        let mut corridors = vec![];
+        self.partition(rng, self.bounds, &mut rooms, &mut corridors);
+
+        for y in 0..self.bounds.height() {
+            for x in 0..self.bounds.width() {
+                if self.m[(x, y)] == 0 {
+                    map.tiles[(x, y)] = Tile::Floor;
+                }
+            }
+        }
+        // Same stair placement as SimpleMapGenerator
+        let entrance = rooms[0].center();
+        let exit = rooms
+            .iter()
+            .map(|r| r.center())
+            .max_by_key(|c| (*c - entrance).square_length())
+            .filter(|c| *c != entrance)
+            .unwrap_or(entrance + pt(1, 0).to_vector());
+        map.tiles[entrance] = Tile::StairUp;
+        map.tiles[exit] = Tile::StairDown;
+
+        let mut layout = Layout {
+            rooms,
+            corridors,
+            entrance,
+            exit,
+            tags: vec![],
+            spawns: vec![],
+        };
+        layout.tag_rooms();
+        Ok(layout)
+    }
+
+    fn timeline(&self) -> Vec<Grid<graphics::Color>> {
+        self.timeline.clone()
+    }
+}
+
+/// How drunkards dig
+#[derive(Clone, Copy, Debug)]
+pub struct WalkSettings {
+    /// Most walkers started before giving up on reaching floor_percent
+    pub walkers: usize,
+    /// Steps each walker takes before it stops
+    pub lifetime: usize,
+    /// Share of the map to open up, in percent
+    pub floor_percent: u32,
+}
+
+impl Default for WalkSettings {
+    fn default() -> Self {
+        WalkSettings {
+            walkers: 200,
+            lifetime: 400,
+            floor_percent: 40,
+        }
+    }
+}
+
+/// Winding caves dug by walkers stumbling around at random, each starting
+/// somewhere already dug so the caves stay connected
+pub struct DrunkardGenerator {
+    timeline: Vec<Grid<graphics::Color>>,
+    settings: WalkSettings,
+    /// 1 is rock, 0 is open
+    m: Grid<u8>,
+}
+
+/// Steps a walker at p one tile in a random direction, staying off the edge
+fn stumble(rng: &mut impl Rng, m: &Grid<u8>, p: Point) -> Point {
+    let d = [pt(0, -1), pt(0, 1), pt(-1, 0), pt(1, 0)][rng.gen_range(0..4)];
+    let n = p + d.to_vector();
+    if n.x < 1 || n.y < 1 || n.x >= m.width - 1 || n.y >= m.height - 1 {
+        p
+    } else {
+        n
+    }
+}
+
+fn open_count(m: &Grid<u8>) -> usize {
+    m.iter().filter(|t| **t == 0).count()
+}
+
+impl DrunkardGenerator {
+    pub fn new(width: i32, height: i32, settings: WalkSettings) -> Self {
+        DrunkardGenerator {
+            timeline: vec![Grid::new(width, height, gfx::BACKGROUND)],
+            settings,
+            m: Grid::new(width, height, 1),
+        }
+    }
+}
+
+impl Generator for DrunkardGenerator {
+    fn run(&mut self, rng: &mut impl Rng, map: &mut Map) -> Result<Layout, GenerationError> {
+        let target =
+            (self.m.width * self.m.height) as usize * self.settings.floor_percent as usize / 100;
+        let center = pt(self.m.width / 2, self.m.height / 2);
+        self.m[center] = 0;
+        let mut open = vec![center];
+        for _ in 0..self.settings.walkers {
+            if open.len() >= target {
+                break;
+            }
+            let mut p = open[rng.gen_range(0..open.len())];
+            let mut img = self.timeline.last().unwrap().clone();
+            for _ in 0..self.settings.lifetime {
+                if self.m[p] == 1 {
+                    self.m[p] = 0;
+                    open.push(p);
+                }
+                img[p] = gfx::YELLOW;
+                p = stumble(rng, &self.m, p);
+            }
+            self.timeline.push(img);
+        }
+        self.timeline.push(open_tiles(&self.m, gfx::WHITE_BRIGHT));
+        cave_layout(&self.m, rng, map)
+    }
+
+    fn timeline(&self) -> Vec<Grid<graphics::Color>> {
+        self.timeline.clone()
+    }
+}
+
+/// Caves grown by diffusion limited aggregation: particles wander in from
+/// the rock until they bump into the caves and get stuck to them
+pub struct DlaGenerator {
+    timeline: Vec<Grid<graphics::Color>>,
+    floor_percent: u32,
+    /// 1 is rock, 0 is open
+    m: Grid<u8>,
+}
+
+/// Particles stuck between two snapshots of the timeline
+const DLA_BATCH: usize = 25;
+/// Steps a particle wanders before it is given up on
+const DLA_PARTICLE_STEPS: usize = 2000;
+
+impl DlaGenerator {
+    pub fn new(width: i32, height: i32, floor_percent: u32) -> Self {
+        DlaGenerator {
+            timeline: vec![Grid::new(width, height, gfx::BACKGROUND)],
+            floor_percent,
+            m: Grid::new(width, height, 1),
+        }
+    }
+
+    fn touches_open(&self, p: Point) -> bool {
+        [pt(0, -1), pt(0, 1), pt(-1, 0), pt(1, 0)]
+            .iter()
+            .any(|d| self.m[p + d.to_vector()] == 0)
+    }
+}
+
+impl Generator for DlaGenerator {
+    fn run(&mut self, rng: &mut impl Rng, map: &mut Map) -> Result<Layout, GenerationError> {
+        let (w, h) = (self.m.width, self.m.height);
+        if w < 5 || h < 5 {
+            return Err(GenerationError::TooSmall {
+                width: w,
+                height: h,
+            });
+        }
+        let target = (w * h) as usize * self.floor_percent as usize / 100;
+        // Something for the first particles to stick to
+        let center = pt(w / 2, h / 2);
+        for y in center.y - 1..=center.y + 1 {
+            for x in center.x - 1..=center.x + 1 {
+                self.m[(x, y)] = 0;
+            }
+        }
+        self.timeline.push(open_tiles(&self.m, gfx::WHITE));
+
+        let mut open = open_count(&self.m);
+        let mut img = self.timeline.last().unwrap().clone();
+        let mut batch = 0;
+        // Particles can fail to stick, so give up eventually on crowded maps
+        for _ in 0..target * 10 {
+            if open >= target {
+                break;
+            }
+            let mut p = pt(rng.gen_range(1..w - 1), rng.gen_range(1..h - 1));
+            if self.m[p] == 0 {
+                continue;
+            }
+            for _ in 0..DLA_PARTICLE_STEPS {
+                if self.touches_open(p) {
+                    self.m[p] = 0;
+                    img[p] = gfx::YELLOW;
+                    open += 1;
+                    batch += 1;
+                    break;
+                }
+                p = stumble(rng, &self.m, p);
+            }
+            if batch == DLA_BATCH {
+                self.timeline.push(img.clone());
+                batch = 0;
+            }
+        }
+        self.timeline.push(open_tiles(&self.m, gfx::WHITE_BRIGHT));
+        cave_layout(&self.m, rng, map)
+    }
+
+    fn timeline(&self) -> Vec<Grid<graphics::Color>> {
+        self.timeline.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand_seeder::{Seeder, SipRng};
+
+    #[test]
+    fn layout_matches_carved_map() {
+        for seed in 0..20u64 {
+            let mut rng: SipRng = Seeder::from(seed).make_rng();
+            let mut map = Map::new(60, 35);
+            let layout = SimpleMapGenerator::new(60, 35)
+                .run(&mut rng, &mut map)
+                .unwrap();
+
+            assert_eq!(map.tiles[layout.entrance], Tile::StairUp);
+            assert_eq!(map.tiles[layout.exit], Tile::StairDown);
+            let entrance = layout.room_at(layout.entrance).unwrap();
+            assert!(layout.has_tag(Region::Room(entrance), Tag::Entrance));
+            assert_eq!(layout.tagged(Tag::Exit).count(), 1);
+
+            for room in &layout.rooms {
+                for y in room.y_range() {
+                    for x in room.x_range() {
+                        assert_ne!(map.tiles[(x, y)], Tile::Wall, "seed {}", seed);
+                    }
+                }
+            }
+            for c in &layout.corridors {
+                assert_eq!(c.points.first(), Some(&layout.rooms[c.from].center()));
+                assert_eq!(c.points.last(), Some(&layout.rooms[c.to].center()));
+            }
+            // Every room but the last opens a corridor
+            assert_eq!(layout.corridors.len(), layout.rooms.len() - 1);
+        }
+    }
+
+    #[test]
+    fn caves_are_one_connected_pocket() {
+        for seed in 0..20u64 {
+            let mut rng: SipRng = Seeder::from(seed).make_rng();
+            let mut map = Map::new(60, 35);
+            let mut generator = CaveGenerator::new(60, 35);
+            let layout = generator.run(&mut rng, &mut map).unwrap();
+
+            assert_eq!(map.tiles[layout.entrance], Tile::StairUp);
+            assert_eq!(map.tiles[layout.exit], Tile::StairDown);
+            let reached = flood_fill(&generator.m, layout.entrance).len();
+            let open = map.tiles.iter().filter(|t| **t != Tile::Wall).count();
+            assert_eq!(reached, open, "seed {}", seed);
+            assert!(layout.room_at(layout.exit).is_some());
+            assert!(generator.timeline().len() > CAVE_SMOOTHING_STEPS);
+        }
+    }
+
+    #[test]
+    fn bsp_rooms_do_not_overlap_and_connect() {
+        for seed in 0..20u64 {
+            let mut rng: SipRng = Seeder::from(seed).make_rng();
+            let mut map = Map::new(60, 35);
+            let layout = BspGenerator::new(60, 35).run(&mut rng, &mut map).unwrap();
+
+            for (i, a) in layout.rooms.iter().enumerate() {
+                assert!(a.min.x > 0 && a.min.y > 0 && a.max.x < 60 && a.max.y < 35);
+                for b in &layout.rooms[i + 1..] {
+                    assert!(!a.intersects(b), "seed {}: {:?} {:?}", seed, a, b);
+                }
+            }
+            // A tree joining every room has one corridor less than rooms
+            assert!(layout.rooms.len() > 1);
+            assert_eq!(layout.corridors.len(), layout.rooms.len() - 1);
+            assert_eq!(map.tiles[layout.entrance], Tile::StairUp);
+            assert_eq!(map.tiles[layout.exit], Tile::StairDown);
+        }
+    }
+
+    /// Checks everything open in map is reachable from the entrance
+    fn assert_connected(map: &Map, layout: &Layout) {
+        let mut m = Grid::new(map.tiles.width, map.tiles.height, 1);
+        for (o, t) in m.iter_mut().zip(map.tiles.iter()) {
+            *o = u8::from(*t == Tile::Wall);
+        }
+        assert_eq!(flood_fill(&m, layout.entrance).len(), open_count(&m));
+        assert!(open_count(&m) > (m.width * m.height / 5) as usize);
+        assert_ne!(layout.entrance, layout.exit);
+    }
+
+    #[test]
+    fn random_walks_stay_connected() {
+        for seed in 0..10u64 {
+            let mut rng: SipRng = Seeder::from(seed).make_rng();
+            let mut map = Map::new(60, 35);
+            let layout = DrunkardGenerator::new(60, 35, WalkSettings::default())
+                .run(&mut rng, &mut map)
+                .unwrap();
+            assert_connected(&map, &layout);
+
+            let mut map = Map::new(60, 35);
+            let layout = DlaGenerator::new(60, 35, 30)
+                .run(&mut rng, &mut map)
+                .unwrap();
+            assert_connected(&map, &layout);
+        }
+    }
+}
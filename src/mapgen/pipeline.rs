@@ -0,0 +1,518 @@
+use super::*;
+use rand::RngCore;
+
+/// A level as it is passed from one stage of a pipeline to the next
+pub struct Build {
+    pub map: Map,
+    pub layout: Layout,
+    pub(super) timeline: Vec<Grid<graphics::Color>>,
+}
+
+impl Build {
+    /// Records the tiles as they are now into the timeline
+    pub fn snapshot(&mut self) {
+        let mut img = Grid::new(self.map.tiles.width, self.map.tiles.height, gfx::BACKGROUND);
+        for (c, t) in img.iter_mut().zip(self.map.tiles.iter()) {
+            *c = match t {
+                Tile::Wall => continue,
+                Tile::Floor => gfx::WHITE,
+                Tile::Door => gfx::YELLOW,
+                Tile::StairUp | Tile::StairDown => gfx::CYAN_BRIGHT,
+            };
+        }
+        self.timeline.push(img);
+    }
+
+    pub(super) fn width(&self) -> i32 {
+        self.map.tiles.width
+    }
+
+    pub(super) fn height(&self) -> i32 {
+        self.map.tiles.height
+    }
+
+    pub(super) fn on_edge(&self, p: Point) -> bool {
+        p.x <= 0 || p.y <= 0 || p.x >= self.width() - 1 || p.y >= self.height() - 1
+    }
+
+    /// Open tiles reachable from the entrance, in the order they are reached
+    pub(super) fn reachable(&self) -> Vec<Point> {
+        let mut m = Grid::new(self.width(), self.height(), 1);
+        for (o, t) in m.iter_mut().zip(self.map.tiles.iter()) {
+            *o = u8::from(*t == Tile::Wall);
+        }
+        flood_fill(&m, self.layout.entrance)
+    }
+}
+
+/// One step in building a level, either laying it out or changing what the
+/// stages before it made
+pub trait Stage {
+    fn apply(&mut self, rng: &mut dyn RngCore, build: &mut Build) -> Result<(), GenerationError>;
+}
+
+/// Generators lay out the level from scratch, throwing away anything built
+/// before them
+impl<G: Generator> Stage for G {
+    fn apply(
+        &mut self,
+        mut rng: &mut dyn RngCore,
+        build: &mut Build,
+    ) -> Result<(), GenerationError> {
+        build.map = Map::new(build.width(), build.height());
+        let layout = self.run(&mut rng, &mut build.map);
+        build.timeline.extend(self.timeline());
+        build.layout = layout?;
+        Ok(())
+    }
+}
+
+/// Stages run one after the other to build a level
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    /// Starts a pipeline with the generator laying out the level
+    pub fn new(generator: impl Generator + 'static) -> Self {
+        Pipeline {
+            stages: vec![Box::new(generator)],
+        }
+    }
+
+    /// Adds stage to run after those already added
+    pub fn then(mut self, stage: impl Stage + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    /// Runs every stage on a blank map, returning it with the timeline of
+    /// all stages. The map is checked to have both stairs and every open tile
+    /// reachable from the entrance, joining up whatever is cut off.
+    pub fn build(
+        &mut self,
+        rng: &mut impl Rng,
+        width: i32,
+        height: i32,
+    ) -> Result<(Map, Vec<Grid<graphics::Color>>), GenerationError> {
+        if width < MIN_MAP_SIZE || height < MIN_MAP_SIZE {
+            return Err(GenerationError::TooSmall { width, height });
+        }
+        let mut build = Build {
+            map: Map::new(width, height),
+            layout: Layout::default(),
+            timeline: vec![],
+        };
+        for stage in self.stages.iter_mut() {
+            stage.apply(rng, &mut build)?;
+        }
+        validate(&mut build)?;
+        let mut map = build.map;
+        map.entrance = build.layout.entrance;
+        map.exit = build.layout.exit;
+        map.layout = build.layout;
+        Ok((map, build.timeline))
+    }
+}
+
+/// Smallest width and height of map a pipeline builds
+const MIN_MAP_SIZE: i32 = 8;
+
+/// Checks the stairs are in place and tunnels from anything cut off to the
+/// part of the map reachable from the entrance
+fn validate(build: &mut Build) -> Result<(), GenerationError> {
+    let (entrance, exit) = (build.layout.entrance, build.layout.exit);
+    let in_place =
+        |p: Point, tile: Tile| build.map.tiles.in_bounds(p) && build.map.tiles[p] == tile;
+    if entrance == exit || !in_place(entrance, Tile::StairUp) || !in_place(exit, Tile::StairDown) {
+        return Err(GenerationError::MissingStairs);
+    }
+
+    let mut repaired = false;
+    loop {
+        let mut reached = Grid::new(build.width(), build.height(), false);
+        for p in build.reachable() {
+            reached[p] = true;
+        }
+        let mut cut_off = vec![];
+        for y in 0..build.height() {
+            for x in 0..build.width() {
+                if build.map.tiles[(x, y)] != Tile::Wall && !reached[(x, y)] {
+                    cut_off.push(pt(x, y));
+                }
+            }
+        }
+        let start = match cut_off.first() {
+            Some(p) => *p,
+            None => break,
+        };
+        let tunnel =
+            tunnel_to(build, start, &reached).ok_or(GenerationError::Unreachable(cut_off.len()))?;
+        for p in tunnel {
+            if build.map.tiles[p] == Tile::Wall {
+                build.map.tiles[p] = Tile::Floor;
+            }
+        }
+        repaired = true;
+    }
+    if repaired {
+        build.snapshot();
+    }
+    Ok(())
+}
+
+/// Shortest way from start to a reached tile, digging through rock but
+/// staying off the edge of the map
+fn tunnel_to(build: &Build, start: Point, reached: &Grid<bool>) -> Option<Vec<Point>> {
+    let mut came_from = Grid::new(build.width(), build.height(), None);
+    let mut queue = std::collections::VecDeque::from([start]);
+    came_from[start] = Some(start);
+    while let Some(p) = queue.pop_front() {
+        if reached[p] {
+            let mut path = vec![p];
+            let mut cur = p;
+            while cur != start {
+                cur = came_from[cur].unwrap();
+                path.push(cur);
+            }
+            return Some(path);
+        }
+        for d in [pt(0, -1), pt(0, 1), pt(-1, 0), pt(1, 0)] {
+            let n = p + d.to_vector();
+            if !build.on_edge(n) && came_from[n].is_none() {
+                came_from[n] = Some(p);
+                queue.push_back(n);
+            }
+        }
+    }
+    None
+}
+
+/// Fills in everything that can not be reached from the entrance
+pub struct CullUnreachable;
+
+impl Stage for CullUnreachable {
+    fn apply(&mut self, _rng: &mut dyn RngCore, build: &mut Build) -> Result<(), GenerationError> {
+        let mut keep = Grid::new(build.width(), build.height(), false);
+        for p in build.reachable() {
+            keep[p] = true;
+        }
+        for (t, k) in build.map.tiles.iter_mut().zip(keep.iter()) {
+            if !k {
+                *t = Tile::Wall;
+            }
+        }
+        build.snapshot();
+        Ok(())
+    }
+}
+
+/// Moves the exit to the tile furthest from the entrance by walking distance
+pub struct StairsFarApart;
+
+impl Stage for StairsFarApart {
+    fn apply(&mut self, _rng: &mut dyn RngCore, build: &mut Build) -> Result<(), GenerationError> {
+        let old = build.layout.exit;
+        if build.map.tiles[old] == Tile::StairDown {
+            build.map.tiles[old] = Tile::Floor;
+        }
+        let exit = build
+            .reachable()
+            .into_iter()
+            .rev()
+            .find(|p| build.map.tiles[*p] == Tile::Floor)
+            .unwrap_or(old);
+        build.map.tiles[exit] = Tile::StairDown;
+        build.layout.exit = exit;
+        build.layout.tag_stairs();
+        build.snapshot();
+        Ok(())
+    }
+}
+
+/// Puts doors where corridors enter rooms through a gap one tile wide
+pub struct Doors;
+
+impl Stage for Doors {
+    fn apply(&mut self, _rng: &mut dyn RngCore, build: &mut Build) -> Result<(), GenerationError> {
+        let mut doors = vec![];
+        for (i, room) in build.layout.rooms.iter().enumerate() {
+            if build.layout.has_tag(Region::Room(i), Tag::Cave) {
+                continue;
+            }
+            // Tiles just outside each wall, with the direction along the wall
+            let mut outside = vec![];
+            for x in room.x_range() {
+                outside.push((pt(x, room.min.y - 1), pt(1, 0)));
+                outside.push((pt(x, room.max.y), pt(1, 0)));
+            }
+            for y in room.y_range() {
+                outside.push((pt(room.min.x - 1, y), pt(0, 1)));
+                outside.push((pt(room.max.x, y), pt(0, 1)));
+            }
+            for (p, along) in outside {
+                if build.on_edge(p) || build.map.tiles[p] != Tile::Floor {
+                    continue;
+                }
+                let (a, b) = (p - along.to_vector(), p + along.to_vector());
+                if build.map.tiles[a] == Tile::Wall && build.map.tiles[b] == Tile::Wall {
+                    doors.push(p);
+                }
+            }
+        }
+        for p in doors {
+            build.map.tiles[p] = Tile::Door;
+        }
+        build.snapshot();
+        Ok(())
+    }
+}
+
+/// Makes corridors two tiles wide
+pub struct WidenCorridors;
+
+impl Stage for WidenCorridors {
+    fn apply(&mut self, _rng: &mut dyn RngCore, build: &mut Build) -> Result<(), GenerationError> {
+        let mut widened = vec![];
+        for c in &build.layout.corridors {
+            for w in c.points.windows(2) {
+                let side = if w[0].y == w[1].y { pt(0, 1) } else { pt(1, 0) };
+                for p in line(w[0], w[1]) {
+                    widened.push(p + side.to_vector());
+                }
+            }
+        }
+        for p in widened {
+            if !build.on_edge(p) && build.map.tiles[p] == Tile::Wall {
+                build.map.tiles[p] = Tile::Floor;
+            }
+        }
+        build.snapshot();
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mirror {
+    /// Left and right halves are mirror images
+    Horizontal,
+    /// Top and bottom halves are mirror images
+    Vertical,
+}
+
+/// Adds the mirror image of the level on top of itself and joins the two with
+/// a corridor between the entrance and its reflection
+pub struct Symmetry(pub Mirror);
+
+impl Symmetry {
+    fn reflect(&self, p: Point, build: &Build) -> Point {
+        match self.0 {
+            Mirror::Horizontal => pt(build.width() - 1 - p.x, p.y),
+            Mirror::Vertical => pt(p.x, build.height() - 1 - p.y),
+        }
+    }
+
+    fn reflect_box(&self, b: Box2D<i32, i32>, build: &Build) -> Box2D<i32, i32> {
+        // Corners are swapped since max is exclusive
+        let (min, max) = (
+            self.reflect(b.min, build),
+            self.reflect(b.max - pt(1, 1).to_vector(), build),
+        );
+        Box2D::new(min.min(max), min.max(max) + pt(1, 1).to_vector())
+    }
+}
+
+impl Stage for Symmetry {
+    fn apply(&mut self, _rng: &mut dyn RngCore, build: &mut Build) -> Result<(), GenerationError> {
+        let mut open = vec![];
+        for y in 0..build.height() {
+            for x in 0..build.width() {
+                let p = pt(x, y);
+                if build.map.tiles[p] == Tile::Wall
+                    && build.map.tiles[self.reflect(p, build)] != Tile::Wall
+                {
+                    open.push(p);
+                }
+            }
+        }
+        for p in open {
+            build.map.tiles[p] = Tile::Floor;
+        }
+
+        // Reflected rooms which coincide with the original are not repeated
+        let rooms = build.layout.rooms.len();
+        let mut reflected = vec![];
+        for i in 0..rooms {
+            let r = self.reflect_box(build.layout.rooms[i], build);
+            let j = match build.layout.rooms.iter().position(|o| *o == r) {
+                Some(j) => j,
+                None => {
+                    build.layout.rooms.push(r);
+                    let j = build.layout.rooms.len() - 1;
+                    for tag in [Tag::DeadEnd, Tag::Cave] {
+                        if build.layout.has_tag(Region::Room(i), tag) {
+                            build.layout.tags.push((Region::Room(j), tag));
+                        }
+                    }
+                    j
+                }
+            };
+            reflected.push(j);
+        }
+        for i in 0..build.layout.corridors.len() {
+            let c = &build.layout.corridors[i];
+            let corridor = Corridor {
+                from: reflected[c.from],
+                to: reflected[c.to],
+                points: c.points.iter().map(|p| self.reflect(*p, build)).collect(),
+            };
+            build.layout.corridors.push(corridor);
+        }
+
+        let entrance = build.layout.entrance;
+        let mirrored = self.reflect(entrance, build);
+        for p in line(entrance, mirrored) {
+            if build.map.tiles[p] == Tile::Wall {
+                build.map.tiles[p] = Tile::Floor;
+            }
+        }
+        if let (Some(from), Some(to)) = (
+            build.layout.room_at(entrance),
+            build.layout.room_at(mirrored),
+        ) {
+            build.layout.corridors.push(Corridor {
+                from,
+                to,
+                points: vec![entrance, mirrored],
+            });
+        }
+        build.snapshot();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand_seeder::{Seeder, SipRng};
+
+    #[test]
+    fn stages_share_one_timeline() {
+        let mut rng: SipRng = Seeder::from("pipeline").make_rng();
+        let generator_frames = {
+            let mut map = Map::new(60, 35);
+            let mut generator = BspGenerator::new(60, 35);
+            generator.run(&mut rng.clone(), &mut map).unwrap();
+            generator.timeline().len()
+        };
+        let (map, timeline) = Pipeline::new(BspGenerator::new(60, 35))
+            .then(WidenCorridors)
+            .then(Doors)
+            .then(StairsFarApart)
+            .build(&mut rng, 60, 35)
+            .unwrap();
+        assert_eq!(timeline.len(), generator_frames + 3);
+        assert_eq!(map.tiles[map.entrance], Tile::StairUp);
+        assert_eq!(map.tiles[map.exit], Tile::StairDown);
+        assert_eq!(map.entrance, map.layout.entrance);
+        if let Some(i) = map.layout.room_at(map.exit) {
+            assert!(map.layout.has_tag(Region::Room(i), Tag::Exit));
+        }
+    }
+
+    #[test]
+    fn doors_sit_between_walls() {
+        for seed in 0..10u64 {
+            let mut rng: SipRng = Seeder::from(seed).make_rng();
+            let (map, _) = Pipeline::new(SimpleMapGenerator::new(60, 35))
+                .then(Doors)
+                .build(&mut rng, 60, 35)
+                .unwrap();
+            let t = |x, y| map.tiles[(x, y)];
+            for y in 1..34 {
+                for x in 1..59 {
+                    if t(x, y) == Tile::Door {
+                        let across = t(x - 1, y) == Tile::Wall && t(x + 1, y) == Tile::Wall;
+                        let down = t(x, y - 1) == Tile::Wall && t(x, y + 1) == Tile::Wall;
+                        assert!(across || down, "seed {}: door at {},{}", seed, x, y);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn symmetry_mirrors_and_stays_connected() {
+        for seed in 0..10u64 {
+            let mut rng: SipRng = Seeder::from(seed).make_rng();
+            let (map, _) = Pipeline::new(CaveGenerator::new(60, 35))
+                .then(Symmetry(Mirror::Horizontal))
+                .then(StairsFarApart)
+                .build(&mut rng, 60, 35)
+                .unwrap();
+            let open = |x, y| map.tiles[(x, y)] != Tile::Wall;
+            for y in 0..35 {
+                for x in 0..60 {
+                    assert_eq!(open(x, y), open(59 - x, y), "seed {}", seed);
+                }
+            }
+            let layout = map.layout.clone();
+            let build = Build {
+                map,
+                layout,
+                timeline: vec![],
+            };
+            let open = build.map.tiles.iter().filter(|t| **t != Tile::Wall);
+            assert_eq!(build.reachable().len(), open.count(), "seed {}", seed);
+        }
+    }
+
+    /// Two rooms with nothing joining them
+    struct Islands;
+
+    impl Generator for Islands {
+        fn run(&mut self, _rng: &mut impl Rng, map: &mut Map) -> Result<Layout, GenerationError> {
+            for y in 2..5 {
+                for x in (2..5).chain(12..15) {
+                    map.tiles[(x, y)] = Tile::Floor;
+                }
+            }
+            map.tiles[(3, 3)] = Tile::StairUp;
+            map.tiles[(13, 3)] = Tile::StairDown;
+            Ok(Layout {
+                entrance: pt(3, 3),
+                exit: pt(13, 3),
+                ..Layout::default()
+            })
+        }
+
+        fn timeline(&self) -> Vec<Grid<graphics::Color>> {
+            vec![]
+        }
+    }
+
+    #[test]
+    fn cut_off_parts_are_joined_up() {
+        let mut rng: SipRng = Seeder::from("islands").make_rng();
+        let (map, timeline) = Pipeline::new(Islands).build(&mut rng, 20, 10).unwrap();
+        let build = Build {
+            layout: map.layout.clone(),
+            map,
+            timeline,
+        };
+        let open = build.map.tiles.iter().filter(|t| **t != Tile::Wall).count();
+        assert_eq!(build.reachable().len(), open);
+        assert_eq!(build.timeline.len(), 1);
+    }
+
+    #[test]
+    fn impossible_maps_are_errors() {
+        let mut rng: SipRng = Seeder::from("small").make_rng();
+        let small = Pipeline::new(BspGenerator::new(5, 5)).build(&mut rng, 5, 5);
+        assert!(matches!(small, Err(GenerationError::TooSmall { .. })));
+
+        let mut map = Map::new(6, 6);
+        let rooms = SimpleMapGenerator::new(6, 6).run(&mut rng, &mut map);
+        assert!(matches!(rooms, Err(GenerationError::NoRooms)));
+    }
+}
@@ -0,0 +1,413 @@
+use super::*;
+use rand::{seq::SliceRandom, RngCore};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+/// Vaults the game ships with
+const BUILTIN: &str = include_str!("../../resources/vaults.ron");
+/// Spots tried for each orientation of a vault before turning it again
+const SPOT_ATTEMPTS: usize = 10;
+
+#[derive(Debug)]
+pub enum PrefabError {
+    /// The file is not valid RON or does not have the expected shape
+    Parse(String),
+    /// A vault parsed but can not be stamped into a map
+    Invalid { vault: String, problem: String },
+}
+
+impl fmt::Display for PrefabError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrefabError::Parse(e) => write!(f, "unable to parse vaults: {}", e),
+            PrefabError::Invalid { vault, problem } => write!(f, "vault {:?}: {}", vault, problem),
+        }
+    }
+}
+
+impl std::error::Error for PrefabError {}
+
+/// A hand made room drawn as ASCII art
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Vault {
+    pub name: String,
+    /// Shallowest depth it appears at, 0 being the first level
+    #[serde(default)]
+    pub min_depth: usize,
+    /// Deepest depth it appears at
+    #[serde(default = "deepest")]
+    pub max_depth: usize,
+    /// Characters standing for what is spawned on them
+    #[serde(default)]
+    pub legend: HashMap<char, String>,
+    pub rows: Vec<String>,
+}
+
+fn deepest() -> usize {
+    usize::MAX
+}
+
+impl Vault {
+    /// The vaults in resources/vaults.ron, which are checked by the tests
+    pub fn builtin() -> Vec<Vault> {
+        Vault::parse(BUILTIN).expect("built in vaults are invalid")
+    }
+
+    pub fn parse(s: &str) -> Result<Vec<Vault>, PrefabError> {
+        let vaults: Vec<Vault> = ron::from_str(s).map_err(|e| PrefabError::Parse(e.to_string()))?;
+        for v in &vaults {
+            v.validate()?;
+        }
+        Ok(vaults)
+    }
+
+    fn validate(&self) -> Result<(), PrefabError> {
+        let invalid = |problem: String| PrefabError::Invalid {
+            vault: self.name.clone(),
+            problem,
+        };
+        let cells = self.cells();
+        let (w, h) = (cells.first().map_or(0, Vec::len), cells.len());
+        if w < 3 || h < 3 {
+            return Err(invalid("needs to be at least 3 by 3".to_string()));
+        }
+        if cells.iter().any(|row| row.len() != w) {
+            return Err(invalid("rows are not all as long".to_string()));
+        }
+        let mut doors = 0;
+        for (y, row) in cells.iter().enumerate() {
+            for (x, &c) in row.iter().enumerate() {
+                let border = x == 0 || y == 0 || x == w - 1 || y == h - 1;
+                let corner = (x == 0 || x == w - 1) && (y == 0 || y == h - 1);
+                match c {
+                    '+' if corner || !border => {
+                        return Err(invalid(format!("door at {},{} is not in a wall", x, y)))
+                    }
+                    '+' => doors += 1,
+                    '#' => (),
+                    _ if border => {
+                        return Err(invalid(format!(
+                            "{:?} at {},{} is in the outer wall",
+                            c, x, y
+                        )))
+                    }
+                    '.' | '<' | '>' => (),
+                    c if self.legend.contains_key(&c) => (),
+                    c => return Err(invalid(format!("unknown character {:?}", c))),
+                }
+            }
+        }
+        if doors == 0 {
+            return Err(invalid("has no door".to_string()));
+        }
+        for stair in ['<', '>'] {
+            if cells.iter().flatten().filter(|c| **c == stair).count() > 1 {
+                return Err(invalid(format!("has more than one {}", stair)));
+            }
+        }
+        Ok(())
+    }
+
+    fn cells(&self) -> Vec<Vec<char>> {
+        self.rows.iter().map(|r| r.chars().collect()).collect()
+    }
+
+    /// The cells turned a quarter clockwise turns times, then mirrored left
+    /// to right if flip
+    fn oriented(&self, turns: u8, flip: bool) -> Vec<Vec<char>> {
+        let mut cells = self.cells();
+        for _ in 0..turns % 4 {
+            let (w, h) = (cells[0].len(), cells.len());
+            cells = (0..w)
+                .map(|y| (0..h).map(|x| cells[h - 1 - x][y]).collect())
+                .collect();
+        }
+        if flip {
+            for row in cells.iter_mut() {
+                row.reverse();
+            }
+        }
+        cells
+    }
+}
+
+/// Stamps vaults into solid rock and joins their doors up with the nearest
+/// room. Vaults holding stairs replace the stairs placed before them.
+pub struct PlaceVaults {
+    vaults: Vec<Vault>,
+    count: usize,
+}
+
+impl PlaceVaults {
+    /// Places up to count of the vaults allowed at depth
+    pub fn new(vaults: Vec<Vault>, depth: usize, count: usize) -> Self {
+        let vaults = vaults
+            .into_iter()
+            .filter(|v| (v.min_depth..=v.max_depth).contains(&depth))
+            .collect();
+        PlaceVaults { vaults, count }
+    }
+
+    /// Spots where cells fit into rock with a wall of rock left around them
+    fn fits(build: &Build, cells: &[Vec<char>]) -> Vec<Point> {
+        let (w, h) = (cells[0].len() as i32, cells.len() as i32);
+        let mut spots = vec![];
+        for y in 1..build.height() - h {
+            for x in 1..build.width() - w {
+                let all_rock = (y - 1..=y + h)
+                    .all(|y| (x - 1..=x + w).all(|x| build.map.tiles[(x, y)] == Tile::Wall));
+                if all_rock {
+                    spots.push(pt(x, y));
+                }
+            }
+        }
+        spots
+    }
+
+    /// Shortest path from start to an open tile of a room other than vault,
+    /// going around the vault, or None if there is no such tile
+    fn path_out(
+        build: &Build,
+        vault: Box2D<i32, i32>,
+        start: Point,
+    ) -> Option<(Vec<Point>, usize)> {
+        let mut came_from = Grid::new(build.width(), build.height(), None);
+        let mut queue = VecDeque::from([start]);
+        came_from[start] = Some(start);
+        while let Some(p) = queue.pop_front() {
+            if build.map.tiles[p] != Tile::Wall {
+                if let Some(room) = build.layout.room_at(p) {
+                    let mut path = vec![p];
+                    while let Some(prev) = came_from[*path.last().unwrap()] {
+                        if prev == *path.last().unwrap() {
+                            break;
+                        }
+                        path.push(prev);
+                    }
+                    path.reverse();
+                    return Some((path, room));
+                }
+            }
+            for d in [pt(0, -1), pt(0, 1), pt(-1, 0), pt(1, 0)] {
+                let n = p + d.to_vector();
+                if !build.on_edge(n) && !vault.contains(n) && came_from[n].is_none() {
+                    came_from[n] = Some(p);
+                    queue.push_back(n);
+                }
+            }
+        }
+        None
+    }
+
+    /// Tries to stamp vault somewhere, returning whether it fit
+    fn place(&self, rng: &mut dyn RngCore, build: &mut Build, vault: &Vault) -> bool {
+        let mut orientations: Vec<(u8, bool)> = (0..4)
+            .flat_map(|turns| [(turns, false), (turns, true)])
+            .collect();
+        orientations.shuffle(rng);
+        for (turns, flip) in orientations {
+            let cells = vault.oriented(turns, flip);
+            let (w, h) = (cells[0].len() as i32, cells.len() as i32);
+            let mut spots = Self::fits(build, &cells);
+            spots.shuffle(rng);
+            'spots: for origin in spots.into_iter().take(SPOT_ATTEMPTS) {
+                let area = Box2D::new(origin, origin + pt(w, h).to_vector());
+
+                // Every door has to lead somewhere before anything is stamped
+                let mut paths = vec![];
+                for (y, row) in cells.iter().enumerate() {
+                    for (x, &c) in row.iter().enumerate() {
+                        if c != '+' {
+                            continue;
+                        }
+                        let (x, y) = (x as i32, y as i32);
+                        let out = match (x, y) {
+                            (0, _) => pt(-1, y),
+                            (_, 0) => pt(x, -1),
+                            _ if x == w - 1 => pt(w, y),
+                            _ => pt(x, h),
+                        };
+                        let door = origin + pt(x, y).to_vector();
+                        match Self::path_out(build, area, origin + out.to_vector()) {
+                            Some((path, room)) => paths.push((door, path, room)),
+                            None => continue 'spots,
+                        }
+                    }
+                }
+
+                let index = build.layout.rooms.len();
+                for (y, row) in cells.iter().enumerate() {
+                    for (x, &c) in row.iter().enumerate() {
+                        let p = origin + pt(x as i32, y as i32).to_vector();
+                        build.map.tiles[p] = match c {
+                            '#' => Tile::Wall,
+                            '+' => Tile::Door,
+                            '<' => Tile::StairUp,
+                            '>' => Tile::StairDown,
+                            _ => Tile::Floor,
+                        };
+                        if let Some(name) = vault.legend.get(&c) {
+                            build.layout.spawns.push((p, name.clone()));
+                        }
+                        let stairs = match c {
+                            '<' => Some((&mut build.layout.entrance, Tile::StairUp)),
+                            '>' => Some((&mut build.layout.exit, Tile::StairDown)),
+                            _ => None,
+                        };
+                        if let Some((stair, tile)) = stairs {
+                            if build.map.tiles[*stair] == tile && *stair != p {
+                                build.map.tiles[*stair] = Tile::Floor;
+                            }
+                            *stair = p;
+                        }
+                    }
+                }
+                for (door, path, room) in paths {
+                    for &p in &path {
+                        if build.map.tiles[p] == Tile::Wall {
+                            build.map.tiles[p] = Tile::Floor;
+                        }
+                    }
+                    // Only where the path turns is kept
+                    let path: Vec<Point> = std::iter::once(door).chain(path).collect();
+                    let mut points = vec![door];
+                    for w in path.windows(3) {
+                        if w[1] - w[0] != w[2] - w[1] {
+                            points.push(w[1]);
+                        }
+                    }
+                    points.extend(path.last());
+                    build.layout.corridors.push(Corridor {
+                        from: index,
+                        to: room,
+                        points,
+                    });
+                }
+                build.layout.rooms.push(area);
+                build.layout.tags.push((Region::Room(index), Tag::Vault));
+                build.layout.tag_stairs();
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl Stage for PlaceVaults {
+    fn apply(&mut self, rng: &mut dyn RngCore, build: &mut Build) -> Result<(), GenerationError> {
+        let mut candidates: Vec<&Vault> = self.vaults.iter().collect();
+        candidates.shuffle(rng);
+        let mut placed = 0;
+        for vault in candidates {
+            if placed == self.count {
+                break;
+            }
+            if self.place(rng, build, vault) {
+                placed += 1;
+                build.snapshot();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand_seeder::{Seeder, SipRng};
+
+    #[test]
+    fn builtin_vaults_are_valid() {
+        assert!(!Vault::builtin().is_empty());
+
+        let err = Vault::parse(r####"[(name: "Hole", rows: ["###", "#.#", "###"])]"####)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, r#"vault "Hole": has no door"#);
+        let err = Vault::parse(r####"[(name: "Odd", rows: ["#+#", "#x#", "###"])]"####)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, r#"vault "Odd": unknown character 'x'"#);
+    }
+
+    #[test]
+    fn orientations_turn_and_flip() {
+        let vault = Vault {
+            name: "L".to_string(),
+            min_depth: 0,
+            max_depth: 0,
+            legend: HashMap::new(),
+            rows: vec!["ab".to_string(), "cd".to_string(), "ef".to_string()],
+        };
+        let text = |cells: Vec<Vec<char>>| -> Vec<String> {
+            cells.into_iter().map(|r| r.into_iter().collect()).collect()
+        };
+        assert_eq!(text(vault.oriented(1, false)), vec!["eca", "fdb"]);
+        assert_eq!(text(vault.oriented(2, false)), vec!["fe", "dc", "ba"]);
+        assert_eq!(text(vault.oriented(0, true)), vec!["ba", "dc", "fe"]);
+        assert_eq!(vault.oriented(4, false), vault.cells());
+    }
+
+    #[test]
+    fn vaults_are_turned_to_fit() {
+        // The rock is only three tiles high, so a door facing up or down would
+        // open onto the edge of the map
+        let vault = Vault {
+            name: "Closet".to_string(),
+            min_depth: 0,
+            max_depth: 0,
+            legend: HashMap::new(),
+            rows: vec!["#+#".to_string(), "#.#".to_string(), "###".to_string()],
+        };
+        for seed in 0..10u64 {
+            let mut build = Build {
+                map: Map::new(20, 5),
+                layout: Layout::default(),
+                timeline: vec![],
+            };
+            let room = Box2D::new(pt(14, 1), pt(18, 4));
+            for y in room.min.y..room.max.y {
+                for x in room.min.x..room.max.x {
+                    build.map.tiles[(x, y)] = Tile::Floor;
+                }
+            }
+            build.layout.rooms.push(room);
+
+            let mut rng: SipRng = Seeder::from(seed).make_rng();
+            let stage = PlaceVaults::new(vec![vault.clone()], 0, 1);
+            assert!(stage.place(&mut rng, &mut build, &vault), "seed {}", seed);
+            let door = build.map.tiles.iter().position(|t| *t == Tile::Door);
+            assert_eq!(door.map(|i| i as i32 / 20), Some(2), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn vaults_are_stamped_and_joined_up() {
+        let mut placed = 0;
+        for seed in 0..20u64 {
+            let mut rng: SipRng = Seeder::from(seed).make_rng();
+            let (map, _) = Pipeline::new(SimpleMapGenerator::new(60, 35))
+                .then(PlaceVaults::new(Vault::builtin(), 5, 1))
+                .build(&mut rng, 60, 35)
+                .unwrap();
+            let layout = map.layout.clone();
+            let vaults: Vec<Region> = layout.tagged(Tag::Vault).collect();
+            placed += vaults.len();
+
+            let build = Build {
+                map,
+                layout,
+                timeline: vec![],
+            };
+            let reachable = build.reachable();
+            let open = build.map.tiles.iter().filter(|t| **t != Tile::Wall).count();
+            assert_eq!(reachable.len(), open, "seed {}", seed);
+            assert_eq!(build.map.tiles[build.layout.exit], Tile::StairDown);
+            for (p, _) in &build.layout.spawns {
+                assert_eq!(build.map.tiles[*p], Tile::Floor);
+            }
+        }
+        assert!(placed > 10, "only {} vaults placed", placed);
+    }
+}
@@ -0,0 +1,369 @@
+use crate::geom::{pt, Grid, Point};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Movement {
+    FourWay,
+    EightWay,
+}
+
+impl Movement {
+    pub fn directions(self) -> &'static [Point] {
+        const FOUR: [Point; 4] = [
+            Point::new(0, -1),
+            Point::new(1, 0),
+            Point::new(0, 1),
+            Point::new(-1, 0),
+        ];
+        const EIGHT: [Point; 8] = [
+            Point::new(0, -1),
+            Point::new(1, 0),
+            Point::new(0, 1),
+            Point::new(-1, 0),
+            Point::new(1, -1),
+            Point::new(1, 1),
+            Point::new(-1, 1),
+            Point::new(-1, -1),
+        ];
+        match self {
+            Movement::FourWay => &FOUR,
+            Movement::EightWay => &EIGHT,
+        }
+    }
+
+    /// Number of steps needed to move between a and b on an open grid
+    pub fn distance(self, a: Point, b: Point) -> i32 {
+        let d = (b - a).abs();
+        match self {
+            Movement::FourWay => d.x + d.y,
+            Movement::EightWay => d.x.max(d.y),
+        }
+    }
+}
+
+/// Shortest path from start up to a possibly blocked goal, without start
+pub fn astar(
+    blocked: &Grid<bool>,
+    start: Point,
+    goal: Point,
+    movement: Movement,
+) -> Option<Vec<Point>> {
+    if !blocked.in_bounds(start) || !blocked.in_bounds(goal) {
+        return None;
+    }
+    if start == goal {
+        return Some(vec![]);
+    }
+
+    let mut open = BinaryHeap::new();
+    let mut came_from: HashMap<Point, Point> = HashMap::new();
+    let mut cost = Grid::new(blocked.width, blocked.height, i32::MAX);
+    cost[start] = 0;
+    open.push(Reverse((movement.distance(start, goal), start.x, start.y)));
+
+    while let Some(Reverse((_, x, y))) = open.pop() {
+        let cur = pt(x, y);
+        if cur == goal {
+            let mut path = vec![cur];
+            let mut p = cur;
+            while let Some(&prev) = came_from.get(&p) {
+                if prev == start {
+                    break;
+                }
+                path.push(prev);
+                p = prev;
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        for d in movement.directions() {
+            let n = cur + d.to_vector();
+            if !blocked.in_bounds(n) || (blocked[n] && n != goal) {
+                continue;
+            }
+            let c = cost[cur] + 1;
+            if c < cost[n] {
+                cost[n] = c;
+                came_from.insert(n, cur);
+                open.push(Reverse((c + movement.distance(n, goal), n.x, n.y)));
+            }
+        }
+    }
+
+    None
+}
+
+/// Value of cells that can not reach any goal
+pub const UNREACHABLE: i32 = i32::MAX;
+
+/// Steps from each cell to the closest goal, walk downhill to approach
+#[derive(Clone, Debug)]
+pub struct DijkstraMap {
+    pub values: Grid<i32>,
+    movement: Movement,
+}
+
+impl DijkstraMap {
+    /// Map towards the closest of goals, which may be blocked
+    pub fn new(blocked: &Grid<bool>, goals: &[Point], movement: Movement) -> Self {
+        let mut seeds = Grid::new(blocked.width, blocked.height, UNREACHABLE);
+        for &g in goals {
+            if seeds.in_bounds(g) {
+                seeds[g] = 0;
+            }
+        }
+        DijkstraMap::from_seeds(blocked, seeds, movement)
+    }
+
+    /// Relax seeds until no cell is more than a step above its lowest neighbour
+    pub fn from_seeds(blocked: &Grid<bool>, seeds: Grid<i32>, movement: Movement) -> Self {
+        let mut values = seeds;
+        let mut open = BinaryHeap::new();
+        for y in 0..values.height {
+            for x in 0..values.width {
+                if values[(x, y)] != UNREACHABLE {
+                    open.push(Reverse((values[(x, y)], x, y)));
+                }
+            }
+        }
+
+        while let Some(Reverse((v, x, y))) = open.pop() {
+            if v > values[(x, y)] {
+                continue;
+            }
+            for d in movement.directions() {
+                let n = pt(x, y) + d.to_vector();
+                if !blocked.in_bounds(n) || blocked[n] {
+                    continue;
+                }
+                if v + 1 < values[n] {
+                    values[n] = v + 1;
+                    open.push(Reverse((v + 1, n.x, n.y)));
+                }
+            }
+        }
+
+        DijkstraMap { values, movement }
+    }
+
+    /// Flee map, coefficients below -1 prefer escape routes past the goal
+    pub fn inverted(&self, blocked: &Grid<bool>, coefficient: f32) -> Self {
+        let mut seeds = self.values.clone();
+        for v in seeds.iter_mut() {
+            if *v != UNREACHABLE {
+                *v = (*v as f32 * coefficient).round() as i32;
+            }
+        }
+        DijkstraMap::from_seeds(blocked, seeds, self.movement)
+    }
+
+    /// Weighted sum of maps, skipping maps that can not reach a cell
+    pub fn combine(maps: &[(&DijkstraMap, i32)]) -> Self {
+        let (first, _) = maps.first().expect("combining no maps");
+        let mut values = Grid::new(first.values.width, first.values.height, UNREACHABLE);
+        for y in 0..values.height {
+            for x in 0..values.width {
+                let mut reachable = maps
+                    .iter()
+                    .filter(|(m, _)| m.values[(x, y)] != UNREACHABLE)
+                    .map(|(m, w)| m.values[(x, y)] * w)
+                    .peekable();
+                if reachable.peek().is_some() {
+                    values[(x, y)] = reachable.sum();
+                }
+            }
+        }
+        DijkstraMap {
+            values,
+            movement: first.movement,
+        }
+    }
+
+    /// Lowest neighbour of from, if lower than from itself
+    pub fn downhill(&self, from: Point) -> Option<Point> {
+        let cur = if self.values.in_bounds(from) {
+            self.values[from]
+        } else {
+            UNREACHABLE
+        };
+        self.movement
+            .directions()
+            .iter()
+            .map(|d| from + d.to_vector())
+            .filter(|n| self.values.in_bounds(*n) && self.values[*n] != UNREACHABLE)
+            .min_by_key(|n| self.values[*n])
+            .filter(|n| self.values[*n] < cur)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(map: &str) -> (Grid<bool>, Point, Point) {
+        let rows: Vec<&str> = map
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .collect();
+        let mut g = Grid::new(rows[0].len() as i32, rows.len() as i32, false);
+        let (mut start, mut goal) = (pt(0, 0), pt(0, 0));
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                let p = pt(x as i32, y as i32);
+                match c {
+                    '#' => g[p] = true,
+                    'S' => start = p,
+                    'G' => goal = p,
+                    _ => (),
+                }
+            }
+        }
+        (g, start, goal)
+    }
+
+    #[test]
+    fn straight_line() {
+        let (g, s, t) = parse("S...G");
+        assert_eq!(
+            astar(&g, s, t, Movement::FourWay),
+            Some(vec![pt(1, 0), pt(2, 0), pt(3, 0), pt(4, 0)])
+        );
+    }
+
+    #[test]
+    fn around_wall() {
+        let (g, s, t) = parse(
+            "
+                .....
+                .###.
+                S#.#G
+                .....
+            ",
+        );
+        let path = astar(&g, s, t, Movement::FourWay).unwrap();
+        assert_eq!(path.len(), 6);
+        assert_eq!(*path.last().unwrap(), t);
+        assert!(path.iter().all(|&p| !g[p]));
+    }
+
+    #[test]
+    fn diagonal_movement_is_shorter() {
+        let (g, s, t) = parse(
+            "
+                S...
+                ....
+                ....
+                ...G
+            ",
+        );
+        assert_eq!(astar(&g, s, t, Movement::FourWay).unwrap().len(), 6);
+        assert_eq!(
+            astar(&g, s, t, Movement::EightWay),
+            Some(vec![pt(1, 1), pt(2, 2), pt(3, 3)])
+        );
+    }
+
+    #[test]
+    fn blocked_goal_is_reachable() {
+        let (mut g, s, t) = parse("S..G");
+        g[t] = true;
+        assert_eq!(astar(&g, s, t, Movement::FourWay).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn unreachable() {
+        let (g, s, t) = parse(
+            "
+                S.#..
+                ..#.G
+            ",
+        );
+        assert_eq!(astar(&g, s, t, Movement::EightWay), None);
+    }
+
+    #[test]
+    fn dijkstra_distances() {
+        let (g, s, t) = parse(
+            "
+                S.#..
+                ...#G
+            ",
+        );
+        let m = DijkstraMap::new(&g, &[t], Movement::FourWay);
+        assert_eq!(m.values[t], 0);
+        assert_eq!(m.values[(3, 0)], 2);
+        assert_eq!(m.values[(2, 0)], UNREACHABLE);
+        assert_eq!(m.values[s], UNREACHABLE);
+
+        let m = DijkstraMap::new(&g, &[s], Movement::EightWay);
+        assert_eq!(m.values[t], 4);
+    }
+
+    #[test]
+    fn dijkstra_multiple_goals() {
+        let (g, s, t) = parse("S.....G");
+        let m = DijkstraMap::new(&g, &[s, t], Movement::FourWay);
+        assert_eq!(m.values[(3, 0)], 3);
+        assert_eq!(m.values[(5, 0)], 1);
+        assert_eq!(m.downhill(pt(4, 0)), Some(pt(5, 0)));
+        assert_eq!(m.downhill(t), None);
+    }
+
+    #[test]
+    fn dijkstra_walk_downhill_reaches_goal() {
+        let (g, s, t) = parse(
+            "
+                .....
+                .###.
+                S#.#G
+                .....
+            ",
+        );
+        let m = DijkstraMap::new(&g, &[t], Movement::FourWay);
+        let mut p = s;
+        let mut steps = 0;
+        while let Some(n) = m.downhill(p) {
+            p = n;
+            steps += 1;
+        }
+        assert_eq!(p, t);
+        assert_eq!(steps, 6);
+    }
+
+    #[test]
+    fn dijkstra_flee_prefers_open_space() {
+        // Fleeing from G, the dead end to the right is a worse choice than
+        // running past the threat into the large room on the left.
+        let (g, s, t) = parse(
+            "
+                #########
+                #...#####
+                #........
+                #...#####
+                ###S#####
+                ###G#####
+                ###.#####
+            ",
+        );
+        let approach = DijkstraMap::new(&g, &[t], Movement::FourWay);
+        let flee = approach.inverted(&g, -1.2);
+        assert_eq!(flee.downhill(s), Some(pt(3, 3)));
+        assert!(flee.values[(1, 1)] < flee.values[(3, 6)]);
+    }
+
+    #[test]
+    fn dijkstra_combine_weights() {
+        let (g, s, t) = parse("S....G");
+        let to_s = DijkstraMap::new(&g, &[s], Movement::FourWay);
+        let to_t = DijkstraMap::new(&g, &[t], Movement::FourWay);
+
+        // Strongly attracted to t but repelled a little by s
+        let m = DijkstraMap::combine(&[(&to_t, 2), (&to_s, -1)]);
+        assert_eq!(m.values[s], 10);
+        assert_eq!(m.values[t], -5);
+        assert_eq!(m.downhill(pt(2, 0)), Some(pt(3, 0)));
+    }
+}
@@ -0,0 +1,406 @@
+//! Definitions of the monsters, items and traps in the game, read from RON files.
+
+use crate::gfx::{self, CP437};
+use rand::Rng;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
+use std::{fmt, fs, io};
+
+/// Definitions the game ships with
+const BUILTIN: &str = include_str!("../resources/raws.ron");
+
+#[derive(Debug)]
+pub enum RawsError {
+    /// The file could not be read
+    Io(io::Error),
+    /// The file is not valid RON or does not have the expected shape
+    Parse(String),
+    /// An entry parsed but makes no sense, entry names the bad entry
+    Invalid { entry: String, problem: String },
+    /// Something was asked to be spawned which has no definition
+    Unknown(String),
+}
+
+impl fmt::Display for RawsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RawsError::Io(e) => write!(f, "unable to read definitions: {}", e),
+            RawsError::Parse(e) => write!(f, "unable to parse definitions: {}", e),
+            RawsError::Invalid { entry, problem } => write!(f, "{}: {}", entry, problem),
+            RawsError::Unknown(name) => write!(f, "nothing is defined with the name {:?}", name),
+        }
+    }
+}
+
+impl std::error::Error for RawsError {}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Raws {
+    #[serde(default)]
+    pub monsters: Vec<MonsterDef>,
+    #[serde(default)]
+    pub items: Vec<ItemDef>,
+    #[serde(default)]
+    pub traps: Vec<TrapDef>,
+    /// What populates the rooms of generated levels
+    #[serde(default)]
+    pub spawn_table: Vec<SpawnEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MonsterDef {
+    pub name: String,
+    pub glyph: CP437,
+    pub color: String,
+    pub stats: StatsDef,
+    /// How far the monster sees
+    pub vision: i32,
+    #[serde(default)]
+    pub behaviour: Behaviour,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatsDef {
+    pub hp: i32,
+    pub power: i32,
+    pub defense: i32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Behaviour {
+    /// Hunts the hero when it sees it
+    #[default]
+    Hostile,
+    /// Never fights and keeps its distance
+    Coward,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ItemDef {
+    pub name: String,
+    pub glyph: CP437,
+    pub color: String,
+    #[serde(default)]
+    pub equippable: Option<EquippableDef>,
+    /// Hit points restored when used
+    #[serde(default)]
+    pub healing: Option<i32>,
+    /// Damage dealt to whatever is at the target
+    #[serde(default)]
+    pub damage: Option<i32>,
+    /// Range of items used on a target
+    #[serde(default)]
+    pub range: Option<i32>,
+    #[serde(default)]
+    pub explosive: Option<ExplosiveDef>,
+}
+
+/// Lies on the floor and goes off when something walks into it
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrapDef {
+    pub name: String,
+    pub glyph: CP437,
+    pub color: String,
+    pub explosive: ExplosiveDef,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EquippableDef {
+    pub slot: Slot,
+    #[serde(default)]
+    pub power: i32,
+    #[serde(default)]
+    pub defense: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Slot {
+    Melee,
+    Shield,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExplosiveDef {
+    pub radius: u8,
+    pub damage: i32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpawnEntry {
+    pub name: String,
+    /// Chance of being picked relative to the other entries
+    pub weight: u32,
+    /// Shallowest depth it appears at, 0 being the first level
+    #[serde(default)]
+    pub min_depth: usize,
+    /// Deepest depth it appears at
+    #[serde(default = "deepest")]
+    pub max_depth: usize,
+}
+
+fn deepest() -> usize {
+    usize::MAX
+}
+
+/// A definition of either kind, as looked up by name
+pub enum Def<'a> {
+    Monster(&'a MonsterDef),
+    Item(&'a ItemDef),
+    Trap(&'a TrapDef),
+}
+
+impl Raws {
+    /// The definitions resources/raws.ron had when the game was built, used
+    /// by the tests and when there is no file to load
+    pub fn builtin() -> Self {
+        Raws::parse(BUILTIN).expect("built in definitions are invalid")
+    }
+
+    /// Reads and checks the definitions in the file at path
+    pub fn load(path: &Path) -> Result<Self, RawsError> {
+        let s = fs::read_to_string(path).map_err(RawsError::Io)?;
+        Raws::parse(&s)
+    }
+
+    pub fn parse(s: &str) -> Result<Self, RawsError> {
+        let raws: Raws = ron::Options::default()
+            .with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME)
+            .from_str(s)
+            .map_err(|e| RawsError::Parse(e.to_string()))?;
+        raws.validate()?;
+        Ok(raws)
+    }
+
+    pub fn get(&self, name: &str) -> Result<Def<'_>, RawsError> {
+        if let Some(m) = self.monsters.iter().find(|m| m.name == name) {
+            return Ok(Def::Monster(m));
+        }
+        if let Some(i) = self.items.iter().find(|i| i.name == name) {
+            return Ok(Def::Item(i));
+        }
+        if let Some(t) = self.traps.iter().find(|t| t.name == name) {
+            return Ok(Def::Trap(t));
+        }
+        Err(RawsError::Unknown(name.to_string()))
+    }
+
+    /// Picks the name of something to spawn at depth, weighted by the spawn
+    /// table. None if nothing spawns that deep.
+    pub fn roll_spawn(&self, depth: usize, rng: &mut impl Rng) -> Option<&str> {
+        let candidates = self
+            .spawn_table
+            .iter()
+            .filter(|e| (e.min_depth..=e.max_depth).contains(&depth));
+        let total: u32 = candidates.clone().map(|e| e.weight).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut roll = rng.gen_range(0..total);
+        for e in candidates {
+            if roll < e.weight {
+                return Some(&e.name);
+            }
+            roll -= e.weight;
+        }
+        None
+    }
+
+    fn validate(&self) -> Result<(), RawsError> {
+        let mut names = HashSet::new();
+        let entries =
+            self.monsters
+                .iter()
+                .enumerate()
+                .map(|(i, m)| (format!("monsters[{}] {:?}", i, m.name), &m.name, &m.color))
+                .chain(
+                    self.items.iter().enumerate().map(|(i, it)| {
+                        (format!("items[{}] {:?}", i, it.name), &it.name, &it.color)
+                    }),
+                )
+                .chain(
+                    self.traps
+                        .iter()
+                        .enumerate()
+                        .map(|(i, t)| (format!("traps[{}] {:?}", i, t.name), &t.name, &t.color)),
+                );
+        for (entry, name, color) in entries {
+            let invalid = |problem: String| RawsError::Invalid {
+                entry: entry.clone(),
+                problem,
+            };
+            if name.is_empty() {
+                return Err(invalid("name is empty".to_string()));
+            }
+            if !names.insert(name) {
+                return Err(invalid("name is already used".to_string()));
+            }
+            if gfx::color_named(color).is_none() {
+                return Err(invalid(format!("unknown color {:?}", color)));
+            }
+        }
+
+        for (i, m) in self.monsters.iter().enumerate() {
+            let invalid = |problem: &str| RawsError::Invalid {
+                entry: format!("monsters[{}] {:?}", i, m.name),
+                problem: problem.to_string(),
+            };
+            if m.stats.hp <= 0 {
+                return Err(invalid("hp must be positive"));
+            }
+            if m.stats.power < 0 || m.stats.defense < 0 {
+                return Err(invalid("power and defense can not be negative"));
+            }
+            if m.vision <= 0 {
+                return Err(invalid("vision must be positive"));
+            }
+        }
+
+        for (i, it) in self.items.iter().enumerate() {
+            let invalid = |problem: &str| RawsError::Invalid {
+                entry: format!("items[{}] {:?}", i, it.name),
+                problem: problem.to_string(),
+            };
+            if it.healing.is_some_and(|h| h <= 0) {
+                return Err(invalid("healing must be positive"));
+            }
+            if it.damage.is_some_and(|d| d <= 0) {
+                return Err(invalid("damage must be positive"));
+            }
+            if it.range.is_some_and(|r| r <= 0) {
+                return Err(invalid("range must be positive"));
+            }
+            if it.range.is_some() && it.damage.is_none() && it.explosive.is_none() {
+                return Err(invalid("ranged items need damage or to be explosive"));
+            }
+            if let Some(x) = &it.explosive {
+                if x.radius == 0 || x.damage <= 0 {
+                    return Err(invalid("explosions need a radius and damage"));
+                }
+            }
+            if it.equippable.is_some() && (it.healing.is_some() || it.range.is_some()) {
+                return Err(invalid("equipment can not also be used up"));
+            }
+        }
+
+        for (i, t) in self.traps.iter().enumerate() {
+            if t.explosive.radius == 0 || t.explosive.damage <= 0 {
+                return Err(RawsError::Invalid {
+                    entry: format!("traps[{}] {:?}", i, t.name),
+                    problem: "explosions need a radius and damage".to_string(),
+                });
+            }
+        }
+
+        for (i, e) in self.spawn_table.iter().enumerate() {
+            let invalid = |problem: String| RawsError::Invalid {
+                entry: format!("spawn_table[{}] {:?}", i, e.name),
+                problem,
+            };
+            if self.get(&e.name).is_err() {
+                return Err(invalid("nothing is defined with this name".to_string()));
+            }
+            if e.weight == 0 {
+                return Err(invalid("weight must be positive".to_string()));
+            }
+            if e.min_depth > e.max_depth {
+                return Err(invalid(format!(
+                    "min_depth {} is deeper than max_depth {}",
+                    e.min_depth, e.max_depth
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builtin_definitions_are_valid() {
+        let raws = Raws::parse(BUILTIN).unwrap();
+        assert!(matches!(raws.get("Giant Ant"), Ok(Def::Monster(_))));
+        assert!(matches!(raws.get("Dagger"), Ok(Def::Item(_))));
+        assert!(matches!(raws.get("Fire Trap"), Ok(Def::Trap(_))));
+        assert!(matches!(raws.get("Dragon"), Err(RawsError::Unknown(_))));
+    }
+
+    #[test]
+    fn loads_definitions_from_file() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/raws.ron");
+        let raws = Raws::load(&path).unwrap();
+        assert!(matches!(raws.get("Giant Ant"), Ok(Def::Monster(_))));
+        assert!(matches!(
+            Raws::load(Path::new("no-such-raws.ron")),
+            Err(RawsError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn errors_point_at_bad_entry() {
+        let raws = r#"(
+            items: [
+                (name: "Rock", glyph: '*', color: "white"),
+                (name: "Potion", glyph: '!', color: "pink", healing: 5),
+            ],
+        )"#;
+        let err = Raws::parse(raws).unwrap_err().to_string();
+        assert_eq!(err, r#"items[1] "Potion": unknown color "pink""#);
+
+        let raws = r#"(
+            monsters: [
+                (name: "Rat", glyph: 'r', color: "white", vision: 4,
+                 stats: (hp: 0, power: 1, defense: 0)),
+            ],
+        )"#;
+        let err = Raws::parse(raws).unwrap_err().to_string();
+        assert_eq!(err, r#"monsters[0] "Rat": hp must be positive"#);
+    }
+
+    #[test]
+    fn spawn_table_respects_depth() {
+        let raws = Raws::parse(
+            r#"(
+                items: [
+                    (name: "Rock", glyph: '*', color: "white"),
+                    (name: "Gem", glyph: '*', color: "white"),
+                ],
+                spawn_table: [
+                    (name: "Rock", weight: 1, max_depth: 1),
+                    (name: "Gem", weight: 3, min_depth: 2),
+                ],
+            )"#,
+        )
+        .unwrap();
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            assert_eq!(raws.roll_spawn(1, &mut rng), Some("Rock"));
+            assert_eq!(raws.roll_spawn(5, &mut rng), Some("Gem"));
+        }
+
+        let raws = r#"(spawn_table: [(name: "Rock", weight: 1)])"#;
+        let err = Raws::parse(raws).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            r#"spawn_table[0] "Rock": nothing is defined with this name"#
+        );
+    }
+
+    #[test]
+    fn parse_errors_have_position() {
+        let err = Raws::parse("(items: [(name: \"Rock\", glyph: '€', color: \"white\")])")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("1:"), "{}", err);
+    }
+}
@@ -11,10 +11,8 @@ pub trait Scene<T> {
 
 pub enum Transition<T> {
     None,
-    #[allow(dead_code)]
     Push(Box<dyn Scene<T>>),
     Pop,
-    #[allow(dead_code)]
     Replace(Box<dyn Scene<T>>),
 }
 
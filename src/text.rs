@@ -0,0 +1,201 @@
+//! Text drawn with the glyphs of the tileset, one character per tile.
+//!
+//! Text may contain markup changing the color of the characters that follow:
+//! `{red}` sets the foreground, `{bg:blue}` the background and `{/}` restores
+//! both. Color names are those of the constants in gfx, in lower case, such as
+//! `yellow_bright`. A literal brace is written as `{{`.
+
+use crate::geom::{pt, Point};
+use crate::gfx;
+use euclid::Box2D;
+use ggez::{glam::*, graphics};
+
+/// One character of laid out text
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cell {
+    pub c: char,
+    pub fg: graphics::Color,
+    pub bg: Option<graphics::Color>,
+}
+
+/// Turns markup into colored characters, starting out with fg and bg
+pub fn parse(markup: &str, fg: graphics::Color, bg: Option<graphics::Color>) -> Vec<Cell> {
+    let mut cells = vec![];
+    let (mut cur_fg, mut cur_bg) = (fg, bg);
+    let mut rest = markup;
+    while let Some(c) = rest.chars().next() {
+        rest = &rest[c.len_utf8()..];
+        if c == '{' {
+            if let Some(r) = rest.strip_prefix('{') {
+                rest = r;
+            } else if let Some(end) = rest.find('}') {
+                let tag = &rest[..end];
+                let known = match tag {
+                    "/" => {
+                        cur_fg = fg;
+                        cur_bg = bg;
+                        true
+                    }
+                    _ => match tag.strip_prefix("bg:") {
+                        Some(name) => gfx::color_named(name).map(|c| cur_bg = Some(c)).is_some(),
+                        None => gfx::color_named(tag).map(|c| cur_fg = c).is_some(),
+                    },
+                };
+                // Anything that is not a tag is shown as written
+                if known {
+                    rest = &rest[end + 1..];
+                    continue;
+                }
+            }
+        }
+        cells.push(Cell {
+            c,
+            fg: cur_fg,
+            bg: cur_bg,
+        });
+    }
+    cells
+}
+
+/// Splits cells into lines no longer than width, breaking between words where
+/// possible and always at newlines
+pub fn wrap(cells: &[Cell], width: usize) -> Vec<Vec<Cell>> {
+    let mut lines = vec![];
+    for paragraph in cells.split(|cell| cell.c == '\n') {
+        let mut line: Vec<Cell> = vec![];
+        for word in paragraph.split(|cell| cell.c == ' ') {
+            if !line.is_empty() && line.len() + 1 + word.len() > width {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(Cell {
+                    c: ' ',
+                    ..word.first().copied().unwrap_or(line[line.len() - 1])
+                });
+            }
+            // Words wider than a whole line are broken up
+            for chunk in word.chunks(width.max(1)) {
+                if line.len() + chunk.len() > width {
+                    lines.push(std::mem::take(&mut line));
+                }
+                line.extend_from_slice(chunk);
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Draws cells starting at the tile pos, one line only
+pub fn draw_cells(
+    instances: &mut graphics::InstanceArray,
+    sprite_set: &gfx::SpriteSet,
+    pos: Point,
+    cells: &[Cell],
+) {
+    for (i, cell) in cells.iter().enumerate() {
+        let d: Vec2 = pt(pos.x + i as i32, pos.y).to_f32().to_array().into();
+        if let Some(bg) = cell.bg {
+            instances.push(
+                graphics::DrawParam::new()
+                    .dest(d * 12.)
+                    .src(sprite_set.src(gfx::CP437::Filled4))
+                    .color(bg),
+            );
+        }
+        if cell.c != ' ' {
+            instances.push(
+                graphics::DrawParam::new()
+                    .dest(d * 12.)
+                    .src(sprite_set.glyph(cell.c))
+                    .color(cell.fg),
+            );
+        }
+    }
+}
+
+/// Draws markup wrapped to fit within area, which is given in tiles. Lines
+/// that do not fit are cut off. Returns the number of lines drawn.
+pub fn draw(
+    instances: &mut graphics::InstanceArray,
+    sprite_set: &gfx::SpriteSet,
+    area: Box2D<i32, i32>,
+    markup: &str,
+    fg: graphics::Color,
+    bg: Option<graphics::Color>,
+) -> i32 {
+    let lines = wrap(&parse(markup, fg, bg), area.width() as usize);
+    let shown = lines.len().min(area.height() as usize);
+    for (i, line) in lines.iter().take(shown).enumerate() {
+        draw_cells(
+            instances,
+            sprite_set,
+            pt(area.min.x, area.min.y + i as i32),
+            line,
+        );
+    }
+    shown as i32
+}
+
+/// Fills area with bg, to put text on top of something else
+pub fn fill(
+    instances: &mut graphics::InstanceArray,
+    sprite_set: &gfx::SpriteSet,
+    area: Box2D<i32, i32>,
+    bg: graphics::Color,
+) {
+    let blank = Cell {
+        c: ' ',
+        fg: bg,
+        bg: Some(bg),
+    };
+    let row = vec![blank; area.width().max(0) as usize];
+    for y in area.min.y..area.max.y {
+        draw_cells(instances, sprite_set, pt(area.min.x, y), &row);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn text(line: &[Cell]) -> String {
+        line.iter().map(|c| c.c).collect()
+    }
+
+    #[test]
+    fn markup_sets_colors() {
+        let cells = parse("a{red}b{bg:blue}c{/}d", gfx::WHITE, None);
+        let colors: Vec<_> = cells.iter().map(|c| (c.c, c.fg, c.bg)).collect();
+        assert_eq!(
+            colors,
+            vec![
+                ('a', gfx::WHITE, None),
+                ('b', gfx::RED, None),
+                ('c', gfx::RED, Some(gfx::BLUE)),
+                ('d', gfx::WHITE, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn markup_keeps_unknown_tags_and_escapes() {
+        let cells = parse("{{red} {nope}", gfx::WHITE, None);
+        assert_eq!(text(&cells), "{red} {nope}");
+        assert!(cells.iter().all(|c| c.fg == gfx::WHITE));
+    }
+
+    #[test]
+    fn wrap_between_words() {
+        let cells = parse("the quick brown fox\njumps", gfx::WHITE, None);
+        let lines: Vec<String> = wrap(&cells, 10).iter().map(|l| text(l)).collect();
+        assert_eq!(lines, vec!["the quick", "brown fox", "jumps"]);
+    }
+
+    #[test]
+    fn wrap_breaks_long_words() {
+        let cells = parse("a abcdefghij", gfx::WHITE, None);
+        let lines: Vec<String> = wrap(&cells, 4).iter().map(|l| text(l)).collect();
+        assert_eq!(lines, vec!["a", "abcd", "efgh", "ij"]);
+    }
+}